@@ -10,5 +10,5 @@ struct Baz;
 fn main() {
     let mut entities = tanya::ecs::Entities::new();
     let mut e0 = [tanya::ecs::Entity::INVALID];
-    entities.create_entities::<(Foo, Bar)>(&mut e0, (&[Foo], &[Bar]));
+    entities.create_entities::<(Foo, Bar)>(&mut e0, (vec![Foo], vec![Bar]));
 }
@@ -2,21 +2,50 @@
 use std::collections::HashMap;
 use std::any::{Any, TypeId};
 use std::ops::Range;
+use std::ptr;
 
-use crate::{EntityId, GroupId, CHUNK_SIZE};
+use crate::{Entity, EntityId, GroupId};
 use crate::storage::{ChunkPtr, ComponentStorage, Storage};
 
 pub type ComponentId = usize;
 
-pub trait Component: Clone + Sized + 'static {}
+/// Storage layout preferred for a component.
+///
+/// Components are always stored in the chunks of the entity group, `tanya-ecs`
+/// has no sparse storage.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StorageHint {
+    /// Stored in the chunks of the entity group.
+    Dense,
+}
+
+pub trait Component: Clone + Sized + 'static {
+    const STORAGE: StorageHint = StorageHint::Dense;
+
+    /// Stable name of the component type, e.g. for serialization.
+    ///
+    /// Implemented by `#[derive(Component)]` from the module path and type name.
+    fn type_name() -> &'static str;
+
+    /// Value for default-constructing the component, if supported.
+    fn default_value() -> Option<Self> {
+        None
+    }
+}
 
 pub type GroupComponentChunks = Vec<ChunkPtr>;
 type Components = Vec<Box<Storage>>;
 type ComponentMap = HashMap<TypeId, ComponentId>;
 
-struct ComponentGroupData {
-    chunks: Vec<GroupComponentChunks>,
-    num_entities: EntityId,
+/// Component chunks of a group, see `query::Query`.
+pub struct ComponentGroupData {
+    pub(crate) components: Vec<ComponentId>,
+    pub(crate) chunks: Vec<GroupComponentChunks>,
+    // Entity of each slot.
+    pub(crate) entities: Vec<Entity>,
+    pub(crate) num_entities: EntityId,
+    // Number of entities per chunk.
+    pub(crate) chunk_capacity: usize,
 }
 
 impl ComponentGroupData {
@@ -25,6 +54,7 @@ impl ComponentGroupData {
         let start = self.num_entities;
         self.num_entities += num as EntityId;
         let end = self.num_entities;
+        self.entities.resize(end as usize, Entity::INVALID);
 
         start..end
     }
@@ -39,15 +69,18 @@ pub struct ComponentGroups {
     group_lut: HashMap<TypeId, usize>,
     components: Components,
     component_lut: ComponentMap,
+    chunk_bytes: usize,
 }
 
 impl ComponentGroups {
-    pub fn new() -> Self {
+    /// Component groups with chunks holding as many entities as fit into `chunk_bytes`.
+    pub fn new(chunk_bytes: usize) -> Self {
         ComponentGroups {
             groups: Vec::new(),
             group_lut: HashMap::new(),
             components: Vec::new(),
             component_lut: HashMap::new(),
+            chunk_bytes,
         }
     }
 
@@ -63,88 +96,179 @@ impl ComponentGroups {
 
     pub fn define_group<'a, G: IComponentGroup<'a>>(&mut self) -> GroupId {
         let group_ty_id = TypeId::of::<G>();
-        *self.group_lut.entry(group_ty_id).or_insert({
-            let id = self.groups.len();
-            G::define_components(&mut self.components, &mut self.component_lut);
-            self.groups.push(ComponentGroupData {
-                chunks: Vec::new(),
-                num_entities: 0,
-            });
-            id
-        })
+        if let Some(id) = self.group_lut.get(&group_ty_id) {
+            return *id;
+        }
+
+        let id = self.groups.len();
+        let components = G::define_components(&mut self.components, &mut self.component_lut);
+        let entity_bytes = components
+            .iter()
+            .map(|component| self.components[*component].component_size())
+            .sum::<usize>();
+        self.groups.push(ComponentGroupData {
+            components,
+            chunks: Vec::new(),
+            entities: Vec::new(),
+            num_entities: 0,
+            chunk_capacity: (self.chunk_bytes / entity_bytes.max(1)).max(1),
+        });
+        self.group_lut.insert(group_ty_id, id);
+        id
     }
 
+    pub fn chunk_capacity(&self, group: GroupId) -> usize {
+        self.groups[group].chunk_capacity
+    }
+
+    /// Allocate `num` uninitialized slots, allocating chunks as required.
     pub fn alloc_slots(&mut self, group: GroupId, num: usize) -> Range<EntityId> {
-        self.groups[group].alloc_slots(num)
+        let components = &mut self.components;
+        let group = &mut self.groups[group];
+        let slots = group.alloc_slots(num);
+
+        let capacity = group.chunk_capacity;
+        let required_chunks = (slots.end as usize + capacity - 1) / capacity;
+        while group.chunks.len() < required_chunks {
+            let chunks = group
+                .components
+                .iter()
+                .map(|component| components[*component].alloc_chunk(capacity))
+                .collect();
+            group.chunks.push(chunks);
+        }
+
+        slots
     }
 
     pub fn get_component_chunks(&mut self, group: GroupId, chunk: usize) -> &mut GroupComponentChunks {
         self.groups[group].get_chunk(chunk)
     }
+
+    /// Assign the entity of an allocated slot.
+    pub fn set_entity(&mut self, group: GroupId, slot: EntityId, entity: Entity) {
+        self.groups[group].entities[slot as usize] = entity;
+    }
+
+    pub(crate) fn component_map(&self) -> &ComponentMap {
+        &self.component_lut
+    }
+
+    pub(crate) fn groups(&self) -> &[ComponentGroupData] {
+        &self.groups
+    }
+}
+
+impl Drop for ComponentGroups {
+    fn drop(&mut self) {
+        // Slots of a group are filled front to back, only the first `num_entities` are initialized.
+        for group in &self.groups {
+            for (i, chunks) in group.chunks.iter().enumerate() {
+                let start = i * group.chunk_capacity;
+                let len = (group.num_entities as usize)
+                    .saturating_sub(start)
+                    .min(group.chunk_capacity);
+                for (component, chunk) in group.components.iter().zip(chunks) {
+                    self.components[*component].drop_slots(chunk, 0..len);
+                }
+            }
+        }
+    }
 }
 
 pub trait IComponentGroup<'a>: 'static {
     type BuildStream<'a>;
-    fn build_entities(
+    /// Take ownership of the first `num` components of each stream column.
+    ///
+    /// The components are moved out of the stream by `build_entities` afterwards.
+    unsafe fn release_stream(stream: &mut Self::BuildStream, num: usize);
+    /// Move components of a released stream into uninitialized slots of a chunk.
+    unsafe fn build_entities(
         chunks: &mut GroupComponentChunks,
         stream: &Self::BuildStream,
         chunk_base: usize,
         entity_base: usize,
         num: usize,
     );
-    fn define_components(components: &mut Components, map: &mut ComponentMap);
+    fn define_components(components: &mut Components, map: &mut ComponentMap) -> Vec<ComponentId>;
 }
 
 impl<'a, C> IComponentGroup<'a> for C
 where
     C: Component,
 {
-    type BuildStream = &'a [C];
-    fn build_entities(
+    type BuildStream = Vec<C>;
+
+    unsafe fn release_stream(stream: &mut Self::BuildStream, num: usize) {
+        assert_eq!(stream.len(), num);
+        stream.set_len(0);
+    }
+
+    unsafe fn build_entities(
         chunks: &mut GroupComponentChunks,
         stream: &Self::BuildStream,
         chunk_base: usize,
         entity_base: usize,
         num: usize,
     ) {
-        let chunk_end = chunk_base + num;
-        let entity_end = entity_base + num;
-
-        let chunk = unsafe { ::std::slice::from_raw_parts_mut(chunks[0].ptr as *mut C, CHUNK_SIZE) };
-        chunk[chunk_base..chunk_end].clone_from_slice(&stream[entity_base..entity_end]);
+        ptr::copy_nonoverlapping(
+            stream.as_ptr().add(entity_base),
+            (chunks[0].ptr as *mut C).add(chunk_base),
+            num,
+        );
     }
 
-    fn define_components(components: &mut Components, map: &mut ComponentMap) {
-        ComponentGroups::define_component::<C>(components, map);
+    fn define_components(components: &mut Components, map: &mut ComponentMap) -> Vec<ComponentId> {
+        vec![ComponentGroups::define_component::<C>(components, map)]
     }
 }
 
-impl<'a, A, B> IComponentGroup<'a> for (A, B)
-where
-    A: Component,
-    B: Component,
-{
-    type BuildStream = (&'a [A], &'a [B]);
-    fn build_entities(
-        chunks: &mut GroupComponentChunks,
-        stream: &Self::BuildStream,
-        chunk_base: usize,
-        entity_base: usize,
-        num: usize,
-    ) {
-        let chunk_end = chunk_base + num;
-        let entity_end = entity_base + num;
+macro_rules! impl_component_group {
+    ($($ty:ident: $idx:tt),*) => {
+        impl<'a, $($ty),*> IComponentGroup<'a> for ($($ty,)*)
+        where
+            $($ty: Component,)*
+        {
+            type BuildStream = ($(Vec<$ty>,)*);
 
-        let chunk_a = unsafe { ::std::slice::from_raw_parts_mut(chunks[0].ptr as *mut A, CHUNK_SIZE) };
-        let chunk_b = unsafe { ::std::slice::from_raw_parts_mut(chunks[1].ptr as *mut B, CHUNK_SIZE) };
+            unsafe fn release_stream(stream: &mut Self::BuildStream, num: usize) {
+                $(
+                    assert_eq!(stream.$idx.len(), num);
+                    stream.$idx.set_len(0);
+                )*
+            }
 
-        chunk_a[chunk_base..chunk_end].clone_from_slice(&(stream.0)[entity_base..entity_end]);
-        chunk_b[chunk_base..chunk_end].clone_from_slice(&(stream.1)[entity_base..entity_end]);
-    }
+            unsafe fn build_entities(
+                chunks: &mut GroupComponentChunks,
+                stream: &Self::BuildStream,
+                chunk_base: usize,
+                entity_base: usize,
+                num: usize,
+            ) {
+                $(
+                    ptr::copy_nonoverlapping(
+                        stream.$idx.as_ptr().add(entity_base),
+                        (chunks[$idx].ptr as *mut $ty).add(chunk_base),
+                        num,
+                    );
+                )*
+            }
 
-    fn define_components(components: &mut Components, map: &mut ComponentMap) {
-        ComponentGroups::define_component::<A>(components, map);
-        ComponentGroups::define_component::<B>(components, map);
-    }
+            fn define_components(components: &mut Components, map: &mut ComponentMap) -> Vec<ComponentId> {
+                vec![$(ComponentGroups::define_component::<$ty>(components, map)),*]
+            }
+        }
+    };
 }
 
+impl_component_group!(A: 0, B: 1);
+impl_component_group!(A: 0, B: 1, C: 2);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9, K: 10);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9, K: 10, L: 11);
@@ -2,6 +2,7 @@ use crate::{EntityId, Generation, GroupId};
 
 const GENERATION_INVALID: Generation = Generation::max_value();
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Entity {
     id: EntityId,
     generation: Generation,
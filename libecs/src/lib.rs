@@ -1,4 +1,4 @@
-#![feature(generic_associated_types)]
+#![feature(generic_associated_types, maybe_uninit)]
 
 pub mod component;
 pub mod entity;
@@ -7,11 +7,14 @@ pub mod storage;
 
 use self::component::{ComponentGroups, IComponentGroup};
 use self::entity::EntityList;
+use self::query::{Filter, Query, QueryContext, QueryIter};
 
-pub use self::component::Component;
+pub use self::component::{Component, StorageHint};
 pub use self::entity::Entity;
+pub use self::query::{Not, With};
 
-const CHUNK_SIZE: usize = 128;
+/// Default chunk size in bytes, see `Entities::with_chunk_bytes`.
+pub const DEFAULT_CHUNK_BYTES: usize = 16 * 1024;
 
 pub type EntityId = u32;
 pub type Generation = u32;
@@ -24,9 +27,14 @@ pub struct Entities {
 
 impl Entities {
     pub fn new() -> Self {
+        Self::with_chunk_bytes(DEFAULT_CHUNK_BYTES)
+    }
+
+    /// Size the chunks of each group to hold as many entities as fit into `chunk_bytes`.
+    pub fn with_chunk_bytes(chunk_bytes: usize) -> Self {
         Entities {
             entities: EntityList::new(),
-            groups: ComponentGroups::new(),
+            groups: ComponentGroups::new(chunk_bytes),
         }
     }
 
@@ -34,30 +42,39 @@ impl Entities {
         self.groups.define_group::<G>()
     }
 
+    /// Create entities moving their components out of the stream.
     pub fn create_entities<'a, G: IComponentGroup<'a>>(
         &mut self,
         entities: &mut [Entity],
-        stream: G::BuildStream,
+        mut stream: G::BuildStream,
     ) {
+        unsafe {
+            G::release_stream(&mut stream, entities.len());
+        }
+
         let group_id = self.define_group::<G>();
+        let chunk_size = self.groups.chunk_capacity(group_id);
 
         let chunk_indices = self.groups.alloc_slots(group_id, entities.len());
-        let chunk_id_start = chunk_indices.start as usize / CHUNK_SIZE;
-        let chunk_id_end = (chunk_indices.end as usize + CHUNK_SIZE - 1) / CHUNK_SIZE;
+        let chunk_id_start = chunk_indices.start as usize / chunk_size;
+        let chunk_id_end = (chunk_indices.end as usize + chunk_size - 1) / chunk_size;
 
         let mut chunk_base = chunk_indices.start as usize;
         let mut cur_entity = 0;
 
         for chunk_id in chunk_id_start..chunk_id_end {
-            let id_end = (chunk_indices.end as usize).min((chunk_id_start + 1) * CHUNK_SIZE);
-            let start = chunk_base % CHUNK_SIZE;
+            let id_end = (chunk_indices.end as usize).min((chunk_id + 1) * chunk_size);
+            let start = chunk_base % chunk_size;
             let num = id_end - chunk_base;
 
             let chunk = self.groups.get_component_chunks(group_id, chunk_id);
-            G::build_entities(chunk, &stream, start, cur_entity, num as _);
+            unsafe {
+                G::build_entities(chunk, &stream, start, cur_entity, num as _);
+            }
 
-            for e in cur_entity..cur_entity + num {
-                entities[0] = self.entities.create_entity(group_id, chunk_id as _);
+            for (i, entity) in entities[cur_entity..cur_entity + num].iter_mut().enumerate() {
+                *entity = self.entities.create_entity(group_id, chunk_id as _);
+                self.groups.set_entity(group_id, (chunk_base + i) as _, *entity);
             }
 
             cur_entity += num;
@@ -65,5 +82,16 @@ impl Entities {
         }
     }
 
-    pub fn query<Q: query::Query>(&mut self) {}
+    /// Iterate over all entities with the components of `Q`.
+    pub fn query<'a, Q: Query<'a>>(&'a mut self) -> QueryIter<'a, Q, ()> {
+        self.query_filtered::<Q, ()>()
+    }
+
+    /// Iterate over all entities with the components of `Q`, which additionally pass the filter `F`.
+    pub fn query_filtered<'a, Q: Query<'a>, F: Filter>(&'a mut self) -> QueryIter<'a, Q, F> {
+        let ctx = QueryContext {
+            comp_map: self.groups.component_map(),
+        };
+        QueryIter::new(ctx, self.groups.groups().iter())
+    }
 }
@@ -1,9 +1,400 @@
+//! Queries over all groups containing a set of components.
+//!
+//! ```ignore
+//! for (entity, pos, vel) in entities.query::<(Entity, &mut Pos, &Vel)>() {
+//!     // ..
+//! }
+//!
+//! // Skip entities with a `Frozen` component, only visit `Player`s.
+//! entities.query_filtered::<(&mut Pos, Option<&Vel>), (With<Player>, Not<Frozen>)>();
+//! ```
+
+use std::any::TypeId;
+use std::collections::HashMap;
 use std::marker::PhantomData;
-use crate::component::IComponentGroup;
 
+use crate::component::{Component, ComponentGroupData, ComponentId, GroupComponentChunks};
+use crate::Entity;
+
+/// Component data required for resolving queries.
+pub struct QueryContext<'a> {
+    pub(crate) comp_map: &'a HashMap<TypeId, ComponentId>,
+}
+
+impl<'a> QueryContext<'a> {
+    /// Index of the component `C` in the chunks of a group.
+    fn column<C: Component>(&self, group: &ComponentGroupData) -> Option<usize> {
+        let id = self.comp_map.get(&TypeId::of::<C>())?;
+        group.components.iter().position(|component| component == id)
+    }
+}
+
+/// Chunks of a group and the index of a component within them.
+pub struct Column<'a, C> {
+    chunks: &'a [GroupComponentChunks],
+    index: usize,
+    _marker: PhantomData<C>,
+}
+
+impl<'a, C: Component> Column<'a, C> {
+    fn new(ctx: &QueryContext<'a>, group: &'a ComponentGroupData) -> Option<Self> {
+        ctx.column::<C>(group).map(|index| Column {
+            chunks: &group.chunks,
+            index,
+            _marker: PhantomData,
+        })
+    }
+
+    unsafe fn slot(&self, chunk: usize, slot: usize) -> *mut C {
+        (self.chunks[chunk][self.index].ptr as *mut C).add(slot)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Data fetched for each entity of a query.
+pub trait Query<'a> {
+    type Item;
+    type State;
+
+    /// Collect the component types accessed by the query.
+    fn access(access: &mut Vec<(TypeId, Access)>);
+    /// Check if the group contains all required components.
+    fn matches(ctx: &QueryContext<'a>, group: &ComponentGroupData) -> bool;
+    fn state(ctx: &QueryContext<'a>, group: &'a ComponentGroupData) -> Self::State;
+    unsafe fn fetch(state: &Self::State, chunk: usize, slot: usize) -> Self::Item;
+}
+
+/// Restricts the groups visited by a query without fetching data.
+pub trait Filter {
+    fn matches(ctx: &QueryContext, group: &ComponentGroupData) -> bool;
+}
+
+/// Only match groups containing the component `C`.
+pub struct With<C>(PhantomData<C>);
+
+/// Only match groups not containing the component `C`.
 pub struct Not<C>(PhantomData<C>);
 
-pub trait Query {}
+impl<'a> Query<'a> for Entity {
+    type Item = Entity;
+    type State = (&'a [Entity], usize);
+
+    fn access(access: &mut Vec<(TypeId, Access)>) {
+        access.push((TypeId::of::<Entity>(), Access::Read));
+    }
+
+    fn matches(_: &QueryContext<'a>, _: &ComponentGroupData) -> bool {
+        true
+    }
+
+    fn state(_: &QueryContext<'a>, group: &'a ComponentGroupData) -> Self::State {
+        (&group.entities, group.chunk_capacity)
+    }
+
+    unsafe fn fetch(state: &Self::State, chunk: usize, slot: usize) -> Self::Item {
+        let (entities, chunk_capacity) = *state;
+        entities[chunk * chunk_capacity + slot]
+    }
+}
+
+impl<'a, C: Component> Query<'a> for &'a C {
+    type Item = &'a C;
+    type State = Column<'a, C>;
+
+    fn access(access: &mut Vec<(TypeId, Access)>) {
+        access.push((TypeId::of::<C>(), Access::Read));
+    }
+
+    fn matches(ctx: &QueryContext<'a>, group: &ComponentGroupData) -> bool {
+        ctx.column::<C>(group).is_some()
+    }
+
+    fn state(ctx: &QueryContext<'a>, group: &'a ComponentGroupData) -> Self::State {
+        Column::new(ctx, group).unwrap()
+    }
+
+    unsafe fn fetch(state: &Self::State, chunk: usize, slot: usize) -> Self::Item {
+        &*state.slot(chunk, slot)
+    }
+}
+
+impl<'a, C: Component> Query<'a> for &'a mut C {
+    type Item = &'a mut C;
+    type State = Column<'a, C>;
+
+    fn access(access: &mut Vec<(TypeId, Access)>) {
+        access.push((TypeId::of::<C>(), Access::Write));
+    }
+
+    fn matches(ctx: &QueryContext<'a>, group: &ComponentGroupData) -> bool {
+        ctx.column::<C>(group).is_some()
+    }
+
+    fn state(ctx: &QueryContext<'a>, group: &'a ComponentGroupData) -> Self::State {
+        Column::new(ctx, group).unwrap()
+    }
+
+    unsafe fn fetch(state: &Self::State, chunk: usize, slot: usize) -> Self::Item {
+        &mut *state.slot(chunk, slot)
+    }
+}
+
+impl<'a, C: Component> Query<'a> for Option<&'a C> {
+    type Item = Option<&'a C>;
+    type State = Option<Column<'a, C>>;
+
+    fn access(access: &mut Vec<(TypeId, Access)>) {
+        access.push((TypeId::of::<C>(), Access::Read));
+    }
+
+    fn matches(_: &QueryContext<'a>, _: &ComponentGroupData) -> bool {
+        true
+    }
+
+    fn state(ctx: &QueryContext<'a>, group: &'a ComponentGroupData) -> Self::State {
+        Column::new(ctx, group)
+    }
+
+    unsafe fn fetch(state: &Self::State, chunk: usize, slot: usize) -> Self::Item {
+        state.as_ref().map(|column| &*column.slot(chunk, slot))
+    }
+}
+
+impl<'a, C: Component> Query<'a> for Option<&'a mut C> {
+    type Item = Option<&'a mut C>;
+    type State = Option<Column<'a, C>>;
+
+    fn access(access: &mut Vec<(TypeId, Access)>) {
+        access.push((TypeId::of::<C>(), Access::Write));
+    }
+
+    fn matches(_: &QueryContext<'a>, _: &ComponentGroupData) -> bool {
+        true
+    }
+
+    fn state(ctx: &QueryContext<'a>, group: &'a ComponentGroupData) -> Self::State {
+        Column::new(ctx, group)
+    }
+
+    unsafe fn fetch(state: &Self::State, chunk: usize, slot: usize) -> Self::Item {
+        state.as_ref().map(|column| &mut *column.slot(chunk, slot))
+    }
+}
+
+impl<C: Component> Filter for With<C> {
+    fn matches(ctx: &QueryContext, group: &ComponentGroupData) -> bool {
+        ctx.column::<C>(group).is_some()
+    }
+}
+
+impl<C: Component> Filter for Not<C> {
+    fn matches(ctx: &QueryContext, group: &ComponentGroupData) -> bool {
+        ctx.column::<C>(group).is_none()
+    }
+}
+
+impl Filter for () {
+    fn matches(_: &QueryContext, _: &ComponentGroupData) -> bool {
+        true
+    }
+}
+
+macro_rules! impl_query {
+    ($($ty:ident: $idx:tt),*) => {
+        impl<'a, $($ty: Query<'a>),*> Query<'a> for ($($ty,)*) {
+            type Item = ($($ty::Item,)*);
+            type State = ($($ty::State,)*);
+
+            fn access(access: &mut Vec<(TypeId, Access)>) {
+                $($ty::access(access);)*
+            }
+
+            fn matches(ctx: &QueryContext<'a>, group: &ComponentGroupData) -> bool {
+                $($ty::matches(ctx, group))&&*
+            }
+
+            fn state(ctx: &QueryContext<'a>, group: &'a ComponentGroupData) -> Self::State {
+                ($($ty::state(ctx, group),)*)
+            }
+
+            unsafe fn fetch(state: &Self::State, chunk: usize, slot: usize) -> Self::Item {
+                ($($ty::fetch(&state.$idx, chunk, slot),)*)
+            }
+        }
+
+        impl<$($ty: Filter),*> Filter for ($($ty,)*) {
+            fn matches(ctx: &QueryContext, group: &ComponentGroupData) -> bool {
+                $($ty::matches(ctx, group))&&*
+            }
+        }
+    };
+}
+
+impl_query!(A: 0);
+impl_query!(A: 0, B: 1);
+impl_query!(A: 0, B: 1, C: 2);
+impl_query!(A: 0, B: 1, C: 2, D: 3);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9, K: 10);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9, K: 10, L: 11);
+
+/// Panics if a component is accessed mutably and additionally by another element of the query.
+fn validate_access<'a, Q: Query<'a>>() {
+    let mut access = Vec::new();
+    Q::access(&mut access);
+    access.sort_by_key(|(ty, _)| *ty);
+    for pair in access.windows(2) {
+        if pair[0].0 == pair[1].0 && (pair[0].1 == Access::Write || pair[1].1 == Access::Write) {
+            panic!(
+                "Component ({:?}) accessed mutably while also accessed in the same query",
+                pair[0].0
+            );
+        }
+    }
+}
+
+/// Iterator over the entities of all groups matching a query.
+pub struct QueryIter<'a, Q: Query<'a>, F> {
+    groups: Vec<(Q::State, &'a ComponentGroupData)>,
+    cur_group: usize,
+    cur_entity: usize,
+    _marker: PhantomData<F>,
+}
+
+impl<'a, Q: Query<'a>, F: Filter> QueryIter<'a, Q, F> {
+    pub(crate) fn new<I>(ctx: QueryContext<'a>, groups: I) -> Self
+    where
+        I: Iterator<Item = &'a ComponentGroupData>,
+    {
+        validate_access::<Q>();
+        let groups = groups
+            .filter(|group| Q::matches(&ctx, group) && F::matches(&ctx, group))
+            .map(|group| (Q::state(&ctx, group), group))
+            .collect();
+
+        QueryIter {
+            groups,
+            cur_group: 0,
+            cur_entity: 0,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, Q: Query<'a>, F: Filter> Iterator for QueryIter<'a, Q, F> {
+    type Item = Q::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (state, group) = self.groups.get(self.cur_group)?;
+            // Slots of a group are filled front to back.
+            if self.cur_entity < group.num_entities as usize {
+                let entity = self.cur_entity;
+                self.cur_entity += 1;
+                let (chunk, slot) = (entity / group.chunk_capacity, entity % group.chunk_capacity);
+                return Some(unsafe { Q::fetch(state, chunk, slot) });
+            }
+
+            self.cur_group += 1;
+            self.cur_entity = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Component, Entities, Entity, Not, With};
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Pos(u32);
+    impl Component for Pos {
+        fn type_name() -> &'static str {
+            "Pos"
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Vel(u32);
+    impl Component for Vel {
+        fn type_name() -> &'static str {
+            "Vel"
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Frozen;
+    impl Component for Frozen {
+        fn type_name() -> &'static str {
+            "Frozen"
+        }
+    }
+
+    fn spawn(entities: &mut Entities) -> Vec<Entity> {
+        // Small chunks for queries spanning multiple chunks.
+        let mut moving = vec![Entity::INVALID; 5];
+        entities.create_entities::<(Pos, Vel)>(&mut moving, ((0..5).map(Pos).collect(), (0..5).map(Vel).collect()));
+        let mut fixed = vec![Entity::INVALID; 2];
+        entities.create_entities::<Pos>(&mut fixed, vec![Pos(10), Pos(11)]);
+        let mut frozen = vec![Entity::INVALID; 1];
+        entities.create_entities::<(Vel, Frozen, Pos)>(&mut frozen, (vec![Vel(1)], vec![Frozen], vec![Pos(20)]));
+        moving.into_iter().chain(fixed).chain(frozen).collect()
+    }
+
+    #[test]
+    fn superset_groups() {
+        let mut entities = Entities::with_chunk_bytes(16);
+        let handles = spawn(&mut entities);
+
+        for (pos, vel) in entities.query::<(&mut Pos, &Vel)>() {
+            pos.0 += vel.0;
+        }
+        let positions = entities
+            .query::<(Entity, &Pos)>()
+            .map(|(entity, pos)| (entity, pos.0))
+            .collect::<Vec<_>>();
+        let expected = handles.iter().cloned().zip(vec![0, 2, 4, 6, 8, 10, 11, 21]).collect::<Vec<_>>();
+        assert_eq!(positions, expected);
+    }
+
+    #[test]
+    fn filters() {
+        let mut entities = Entities::with_chunk_bytes(16);
+        spawn(&mut entities);
+
+        let moving = entities
+            .query_filtered::<&Pos, (With<Vel>, Not<Frozen>)>()
+            .map(|pos| pos.0)
+            .collect::<Vec<_>>();
+        assert_eq!(moving, vec![0, 1, 2, 3, 4]);
+
+        let velocities = entities
+            .query_filtered::<Option<&Vel>, Not<Frozen>>()
+            .map(|vel| vel.map(|vel| vel.0))
+            .collect::<Vec<_>>();
+        assert_eq!(velocities, vec![Some(0), Some(1), Some(2), Some(3), Some(4), None, None]);
 
-impl<'a, G> Query for G where G: IComponentGroup<'a> {}
+        for vel in entities.query::<Option<&mut Vel>>() {
+            if let Some(vel) = vel {
+                vel.0 = 7;
+            }
+        }
+        assert!(entities.query::<&Vel>().all(|vel| vel.0 == 7));
+    }
 
+    #[test]
+    #[should_panic(expected = "accessed mutably")]
+    fn aliased_access() {
+        let mut entities = Entities::new();
+        entities.query::<(&mut Pos, Option<&Pos>)>();
+    }
+}
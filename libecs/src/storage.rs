@@ -1,6 +1,9 @@
-use crate::CHUNK_SIZE;
+use std::mem::{self, MaybeUninit};
+use std::ops::Range;
+use std::ptr;
+use std::slice;
 
-type Chunk<C> = Box<[C; CHUNK_SIZE]>;
+type Chunk<C> = Box<[MaybeUninit<C>]>;
 
 pub struct ChunkPtr {
     pub ptr: *mut (),
@@ -18,14 +21,33 @@ impl<C> ComponentStorage<C> {
 }
 
 pub trait Storage {
-    fn alloc_chunk(&mut self) -> ChunkPtr;
+    /// Size of a single component in bytes.
+    fn component_size(&self) -> usize;
+    fn alloc_chunk(&mut self, capacity: usize) -> ChunkPtr;
+    /// Drop the components of initialized slots of a chunk.
+    fn drop_slots(&self, chunk: &ChunkPtr, slots: Range<usize>);
 }
 
 impl<C> Storage for ComponentStorage<C> {
-    fn alloc_chunk(&mut self) -> ChunkPtr {
-        self.chunks.push(Box::new(unsafe { ::std::mem::zeroed() }));
+    fn component_size(&self) -> usize {
+        mem::size_of::<C>()
+    }
+
+    fn alloc_chunk(&mut self, capacity: usize) -> ChunkPtr {
+        let chunk = (0..capacity)
+            .map(|_| MaybeUninit::uninitialized())
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+        self.chunks.push(chunk);
         ChunkPtr {
             ptr: self.chunks.last_mut().unwrap().as_mut_ptr() as *mut _,
         }
     }
+
+    fn drop_slots(&self, chunk: &ChunkPtr, slots: Range<usize>) {
+        unsafe {
+            let start = (chunk.ptr as *mut C).add(slots.start);
+            ptr::drop_in_place(slice::from_raw_parts_mut(start, slots.end - slots.start));
+        }
+    }
 }
@@ -0,0 +1,97 @@
+//! Entity id allocator benchmarks against the previous linked list allocator.
+//!
+//! ```sh
+//! cargo bench --bench free_list
+//! ```
+
+#![feature(test)]
+
+extern crate test;
+
+use test::Bencher;
+
+type EntityId = u32;
+
+#[allow(dead_code)]
+#[path = "../../src/free_list.rs"]
+mod free_list;
+#[allow(dead_code)]
+mod linked_list;
+
+const NUM_IDS: EntityId = 4096;
+// Odd multiplier permuting `0..NUM_IDS`.
+const SCATTER: EntityId = 2_654_435_761;
+
+macro_rules! bench_allocator {
+    ($name:ident, $allocator:ty) => {
+        mod $name {
+            use super::*;
+
+            type Allocator = $allocator;
+
+            fn allocated() -> Allocator {
+                let mut allocator = Allocator::with_capacity(NUM_IDS);
+                allocator.allocate(NUM_IDS).unwrap();
+                allocator
+            }
+
+            /// Free all ids one by one in order, merging into a single range.
+            #[bench]
+            fn deallocate_sequential(b: &mut Bencher) {
+                b.iter(|| {
+                    let mut allocator = allocated();
+                    for id in 0..NUM_IDS {
+                        allocator.deallocate(id..id + 1);
+                    }
+                    allocator
+                });
+            }
+
+            /// Free every other id, then the remaining ones, creating many ranges.
+            #[bench]
+            fn deallocate_interleaved(b: &mut Bencher) {
+                b.iter(|| {
+                    let mut allocator = allocated();
+                    for id in (0..NUM_IDS).step_by(2) {
+                        allocator.deallocate(id..id + 1);
+                    }
+                    for id in (1..NUM_IDS).step_by(2) {
+                        allocator.deallocate(id..id + 1);
+                    }
+                    allocator
+                });
+            }
+
+            /// Free all ids in a scattered order.
+            #[bench]
+            fn deallocate_scattered(b: &mut Bencher) {
+                b.iter(|| {
+                    let mut allocator = allocated();
+                    for i in 0..NUM_IDS {
+                        let id = i.wrapping_mul(SCATTER) % NUM_IDS;
+                        allocator.deallocate(id..id + 1);
+                    }
+                    allocator
+                });
+            }
+
+            /// Allocate single ids from a fragmented free list.
+            #[bench]
+            fn allocate_fragmented(b: &mut Bencher) {
+                b.iter(|| {
+                    let mut allocator = allocated();
+                    for id in (0..NUM_IDS).step_by(2) {
+                        allocator.deallocate(id..id + 1);
+                    }
+                    while let Some(range) = allocator.allocate(1) {
+                        test::black_box(range);
+                    }
+                    allocator
+                });
+            }
+        }
+    };
+}
+
+bench_allocator!(btree, free_list::Allocator);
+bench_allocator!(list, linked_list::Allocator);
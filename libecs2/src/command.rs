@@ -0,0 +1,133 @@
+//! Deferred structural changes.
+//!
+//! Creating or freeing entities and adding or removing components requires
+//! exclusive access to the world. Jobs record these changes into a
+//! `CommandBuffer` instead, which is applied at a sync point by `World::apply`.
+//!
+//! ```ignore
+//! let mut commands = world.commands();
+//!
+//! // inside a job
+//! let mut entities = [Entity::INVALID; 2];
+//! commands.create_entities::<(Pos, Vel)>(&mut entities, (pos, vel));
+//! commands.add_component(entities[0], Player);
+//!
+//! // sync point
+//! world.apply(commands);
+//! ```
+
+use std::mem;
+use std::ops::Range;
+
+use super::free_list::Reserver;
+use super::{Component, Entity, EntityId, IComponentGroup, World};
+
+trait Command: Send {
+    fn apply(self: Box<Self>, world: &mut World);
+}
+
+struct Deferred<F>(F);
+
+impl<F> Command for Deferred<F>
+where
+    F: FnOnce(&mut World) + Send,
+{
+    fn apply(self: Box<Self>, world: &mut World) {
+        let Deferred(command) = *self;
+        command(world)
+    }
+}
+
+/// Records structural changes to be applied in order by `World::apply`.
+///
+/// Entities created by the buffer get their ids reserved immediately, the handles
+/// can be used by subsequent commands of the same buffer or stored in components.
+///
+/// Reserved entities aren't alive before the buffer is applied. Dropping the
+/// buffer without applying it releases the reserved ids again.
+///
+/// Commands on entities which aren't alive anymore when applied are skipped,
+/// e.g. if another buffer freed them first.
+pub struct CommandBuffer {
+    reserver: Reserver,
+    reserved: Vec<Range<EntityId>>,
+    commands: Vec<Box<Command>>,
+}
+
+impl CommandBuffer {
+    pub(crate) fn new(reserver: Reserver) -> Self {
+        CommandBuffer {
+            reserver,
+            reserved: Vec::new(),
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// Reserve entity ids and create the entities with the components of `G` on apply.
+    pub fn create_entities<G>(&mut self, entities: &mut [Entity], stream: G::BuildStream)
+    where
+        G: IComponentGroup<'static>,
+        G::BuildStream: Send,
+    {
+        let ids = self.reserver.reserve(entities.len());
+        self.reserved.push(ids.clone());
+        for (entity, id) in entities.iter_mut().zip(ids) {
+            // Reserved ids have never been used before.
+            *entity = Entity { id, generation: 0 };
+        }
+
+        let mut entities = entities.to_vec();
+        self.push(move |world| world.place_reserved::<G>(&mut entities, stream));
+    }
+
+    pub fn free_entities(&mut self, entities: &[Entity]) {
+        let entities = entities.to_vec();
+        self.push(move |world| world.free_entities(&entities));
+    }
+
+    pub fn add_component<C: Component>(&mut self, entity: Entity, value: C) {
+        self.push(move |world| {
+            if world.is_alive(entity) {
+                world.add_component(entity, value);
+            }
+        });
+    }
+
+    pub fn remove_component<C: Component>(&mut self, entity: Entity) {
+        self.push(move |world| {
+            if world.is_alive(entity) {
+                world.remove_component::<C>(entity);
+            }
+        });
+    }
+
+    fn push<F>(&mut self, command: F)
+    where
+        F: FnOnce(&mut World) + Send + 'static,
+    {
+        self.commands.push(Box::new(Deferred(command)));
+    }
+
+    pub(crate) fn apply(mut self, world: &mut World) {
+        self.reserved.clear();
+        for command in mem::replace(&mut self.commands, Vec::new()) {
+            command.apply(world);
+        }
+    }
+}
+
+impl Drop for CommandBuffer {
+    fn drop(&mut self) {
+        for ids in self.reserved.drain(..) {
+            self.reserver.release(ids);
+        }
+    }
+}
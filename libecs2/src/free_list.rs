@@ -1,136 +1,182 @@
-use std::collections::LinkedList;
+//! Allocator for entity ids.
+//!
+//! Free ids are stored as disjoint ranges in a tree ordered by their start.
+//! Adjacent ranges are merged on deallocation, allocating and deallocating is
+//! `O(log n)` in the number of free ranges. Allocations take the lowest free
+//! ids to keep the entity table dense.
+//!
+//! Ids past the end of the allocator can be reserved lock-free from multiple
+//! threads with a `Reserver`, e.g. by command buffers. Reserved ids have never
+//! been used before and are added to the allocator by `append` or
+//! `append_allocated` before their first use. Reservations which end up unused
+//! are handed back with `Reserver::release`.
+
+use std::collections::BTreeMap;
 use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 use super::EntityId;
 
-// TODO: mostly from the dx12 free list allocator of gfx-rs
-//       modify it to fit the needs for this use-case better.
+/// Shared handle for reserving fresh ids of an allocator.
+#[derive(Clone, Debug)]
+pub struct Reserver {
+    // End of the id range including reserved ids.
+    end: Arc<AtomicUsize>,
+    // Reserved ranges which won't be used.
+    released: Arc<Mutex<Vec<Range<EntityId>>>>,
+}
+
+impl Reserver {
+    fn new(end: EntityId) -> Self {
+        Reserver {
+            end: Arc::new(AtomicUsize::new(end as usize)),
+            released: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Reserve `num` consecutive ids.
+    pub fn reserve(&self, num: usize) -> Range<EntityId> {
+        let start = self.end.fetch_add(num, Ordering::SeqCst);
+        assert!(
+            start + num <= EntityId::max_value() as usize,
+            "Entity ids exhausted"
+        );
+        start as EntityId..(start + num) as EntityId
+    }
+
+    /// End of the id range including reserved ids.
+    pub fn end(&self) -> EntityId {
+        self.end.load(Ordering::SeqCst) as EntityId
+    }
+
+    /// Hand back reserved ids which won't be used, e.g. by a dropped command buffer.
+    pub fn release(&self, ids: Range<EntityId>) {
+        if ids.start < ids.end {
+            self.released.lock().unwrap().push(ids);
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Allocator {
+    // Number of ids added to the allocator.
     size: EntityId,
-    free_list: LinkedList<Range<EntityId>>,
+    // Free ranges as `start -> end`.
+    free: BTreeMap<EntityId, EntityId>,
+    reserver: Reserver,
 }
 
 impl Allocator {
     pub fn new() -> Self {
         Allocator {
             size: 0,
-            free_list: LinkedList::new(),
+            free: BTreeMap::new(),
+            reserver: Reserver::new(0),
         }
     }
 
     pub fn with_capacity(size: EntityId) -> Self {
-        // Node spanning the whole range.
-        let node = Range {
-            start: 0,
-            end: size,
-        };
-        let mut free_list = LinkedList::new();
-        free_list.push_front(node);
-        Allocator { size, free_list }
+        let mut free = BTreeMap::new();
+        if size > 0 {
+            free.insert(0, size);
+        }
+        Allocator {
+            size,
+            free,
+            reserver: Reserver::new(size),
+        }
+    }
+
+    pub fn reserver(&self) -> Reserver {
+        self.reserver.clone()
+    }
+
+    /// Reserve `num` consecutive ids, see `Reserver::reserve`.
+    pub fn reserve(&self, num: usize) -> Range<EntityId> {
+        self.reserver.reserve(num)
+    }
+
+    /// End of the id range including reserved ids.
+    pub fn reserved_end(&self) -> EntityId {
+        self.reserver.end()
+    }
+
+    /// Take the released ranges below `end`, see `Reserver::release`.
+    ///
+    /// The ids aren't free yet, they have to be appended before deallocating them.
+    pub fn take_released(&self, end: EntityId) -> Vec<Range<EntityId>> {
+        let mut released = self.reserver.released.lock().unwrap();
+        let (taken, kept) = released.drain(..).partition(|ids| ids.end <= end);
+        *released = kept;
+        taken
     }
 
+    /// Add the next `num` reserved ids to the free ranges.
     pub fn append(&mut self, num: EntityId) {
-        self.deallocate(self.size..self.size + num);
-        self.size += num;
+        let start = self.size;
+        self.append_allocated(num);
+        self.deallocate(start..start + num);
     }
 
-    pub fn allocate(&mut self, mut size: EntityId) -> Option<Range<EntityId>> {
-        // TODO: refactor
+    /// Add the next `num` reserved ids without adding them to the free ranges.
+    pub fn append_allocated(&mut self, num: EntityId) {
+        self.size += num;
+        debug_assert!(self.size <= self.reserved_end());
+    }
 
+    /// Allocate up to `size` consecutive ids from the lowest free range.
+    ///
+    /// Returns `None` if all ids are in use.
+    pub fn allocate(&mut self, size: EntityId) -> Option<Range<EntityId>> {
         if size == 0 {
             return Some(Range { start: 0, end: 0 });
         }
 
-        // Find first node  ..
-        let mut split_index = None;
-        for (index, _) in self.free_list.iter().enumerate() {
-            if true {
-                // Found a candidate.
-                split_index = Some(index);
-                break;
-            }
-        }
+        let (start, end) = self.free.iter().next().map(|(start, end)| (*start, *end))?;
+        self.free.remove(&start);
 
-        split_index.map(|index| {
-            let mut tail = self.free_list.split_off(index);
-
-            // The first list element of `tail` will be split into two nodes.
-            let mut node = tail.pop_front().unwrap();
-            size = size.min(node.end - node.start);
-            let allocated = Range {
-                start: node.start,
-                end: node.start + size,
-            };
-            node.start += size;
-
-            // Our new list will look like this considering our 2nd node part
-            // is not empty:
-            // Before: [old list] -- [allocated|node] -- [tail]
-            // After:  [old list] -- [node] -- [tail] || [allocated]
-            if node.start < node.end {
-                self.free_list.push_back(node);
-            }
-            self.free_list.append(&mut tail);
+        let allocated = start..start + size.min(end - start);
+        if allocated.end < end {
+            self.free.insert(allocated.end, end);
+        }
 
-            allocated
-        })
+        Some(allocated)
     }
 
-    pub fn deallocate(&mut self, mut range: Range<EntityId>) {
+    pub fn deallocate(&mut self, range: Range<EntityId>) {
         // early out for invalid or empty ranges
         if range.end <= range.start {
             return;
         }
 
-        // Find node where we want to insert the range.
-        // We aim to merge consecutive nodes into larger ranges, so we maintain
-        // a sorted list.
-        let mut insert_index = self.free_list.len(); // append at the end
-        for (index, node) in self.free_list.iter().enumerate() {
-            if node.start > range.start {
-                // Found a better place!
-                insert_index = index;
-                break;
+        debug_assert!(range.end <= self.size);
+        debug_assert!(
+            self.free.range(range.clone()).next().is_none(),
+            "Ids {:?} already free",
+            range
+        );
+
+        let Range { mut start, mut end } = range;
+        if let Some((prev_start, prev_end)) = self.free.range(..start).next_back().map(|(s, e)| (*s, *e)) {
+            debug_assert!(prev_end <= start, "Ids {:?} already free", start..end);
+            if prev_end == start {
+                self.free.remove(&prev_start);
+                start = prev_start;
             }
         }
+        if let Some(next_end) = self.free.remove(&end) {
+            end = next_end;
+        }
 
-        // New list: [head] -- [node] -- [tail]
-        let mut tail = self.free_list.split_off(insert_index);
-
-        // Try merge with prior node from [head]
-        let pre_node = self.free_list.pop_back();
-        pre_node.map(|pre_node| {
-            if pre_node.end == range.start {
-                // Merge both nodes
-                range.start = pre_node.start;
-            } else {
-                // Re-insert the previous node
-                self.free_list.push_back(pre_node);
-            }
-        });
-
-        // Try merge with next node from [tail]
-        let next_node = tail.pop_front();
-        next_node.map(|next_node| {
-            if range.end == next_node.start {
-                // Merge both nodes
-                range.end = next_node.end;
-            } else {
-                // Re-insert the next node
-                tail.push_front(next_node);
-            }
-        });
-
-        self.free_list.push_back(range);
-        self.free_list.append(&mut tail);
+        self.free.insert(start, end);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::Allocator;
+    use std::thread;
 
     #[test]
     fn test_allocate() {
@@ -155,4 +201,45 @@ mod tests {
         allocator.deallocate(middle);
         assert_eq!(Some(4..8), allocator.allocate(9));
     }
+
+    #[test]
+    fn test_merge_interleaved() {
+        let mut allocator = Allocator::with_capacity(1024);
+        assert_eq!(Some(0..1024), allocator.allocate(1024));
+
+        for id in (0..1024).step_by(2).rev() {
+            allocator.deallocate(id..id + 1);
+        }
+        assert_eq!(allocator.free.len(), 512);
+        for id in (1..1024).step_by(2) {
+            allocator.deallocate(id..id + 1);
+        }
+        assert_eq!(allocator.free.len(), 1);
+        assert_eq!(Some(0..1024), allocator.allocate(2048));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut allocator = Allocator::with_capacity(4);
+        let threads = (0..4)
+            .map(|_| {
+                let reserver = allocator.reserver();
+                thread::spawn(move || (0..64).map(|_| reserver.reserve(2)).collect::<Vec<_>>())
+            })
+            .collect::<Vec<_>>();
+
+        let mut reserved = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+        reserved.sort_by_key(|range| range.start);
+        for (i, range) in reserved.iter().enumerate() {
+            let start = 4 + 2 * i as u32;
+            assert_eq!(*range, start..start + 2);
+        }
+        assert_eq!(allocator.reserved_end(), 4 + 512);
+
+        allocator.append(512);
+        assert_eq!(Some(0..516), allocator.allocate(1024));
+    }
 }
@@ -0,0 +1,227 @@
+//! Parent/child relationships between entities and transform propagation.
+//!
+//! The relationship is stored in the `Parent` and `Children` components, which
+//! are maintained by `World::set_parent` and `World::remove_parent`. Freeing an
+//! entity detaches it from its parent and turns its children into roots.
+//!
+//! ```ignore
+//! world.set_parent(wheel, car);
+//! for (entity, depth) in world.bfs(car) {
+//!     // ..
+//! }
+//!
+//! // Compute `L::World` of all entities with a local transform `L`.
+//! world.propagate_transforms::<LocalTransform>();
+//! ```
+
+use std::any::TypeId;
+use std::collections::VecDeque;
+use std::mem;
+
+use crate::{Component, Entity, Not, With, World};
+
+/// Parent of an entity.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Parent(Entity);
+
+impl Parent {
+    pub fn entity(&self) -> Entity {
+        self.0
+    }
+}
+
+impl Component for Parent {
+    fn type_name() -> &'static str {
+        concat!(module_path!(), "::Parent")
+    }
+}
+
+/// Children of an entity in insertion order.
+#[derive(Clone, Debug, Default)]
+pub struct Children(Vec<Entity>);
+
+impl Children {
+    pub fn entities(&self) -> &[Entity] {
+        &self.0
+    }
+}
+
+impl Component for Children {
+    fn type_name() -> &'static str {
+        concat!(module_path!(), "::Children")
+    }
+}
+
+/// Transform of an entity relative to its parent.
+pub trait LocalTransform: Component {
+    /// Transform relative to the hierarchy roots.
+    type World: Component;
+
+    /// Combine with the world transform of the parent, `None` for roots.
+    fn to_world(&self, parent: Option<&Self::World>) -> Self::World;
+}
+
+/// Breadth-first iterator over a subtree, see `World::bfs`.
+pub struct Bfs<'a> {
+    world: &'a World,
+    queue: VecDeque<(Entity, usize)>,
+}
+
+impl<'a> Iterator for Bfs<'a> {
+    type Item = (Entity, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (entity, depth) = self.queue.pop_front()?;
+        for child in self.world.children(entity) {
+            self.queue.push_back((*child, depth + 1));
+        }
+        Some((entity, depth))
+    }
+}
+
+/// Level by level traversal of the hierarchy, see `World::transform_levels`.
+///
+/// The buffers of the current and the next level are reused for all levels.
+pub(crate) struct TransformLevels {
+    level: Vec<Entity>,
+    next: Vec<Entity>,
+    started: bool,
+}
+
+impl TransformLevels {
+    /// Advance to the next level, the roots on the first call.
+    pub(crate) fn next(&mut self, world: &World) -> Option<&[Entity]> {
+        if self.started {
+            self.next.clear();
+            for entity in &self.level {
+                self.next.extend_from_slice(world.children(*entity));
+            }
+            mem::swap(&mut self.level, &mut self.next);
+        }
+        self.started = true;
+
+        if self.level.is_empty() {
+            None
+        } else {
+            Some(&self.level)
+        }
+    }
+}
+
+impl World {
+    /// Attach an entity to a new parent, detaching it from the previous one.
+    ///
+    /// Panics if `parent` is a descendant of `child`.
+    pub fn set_parent(&mut self, child: Entity, parent: Entity) {
+        assert!(self.is_alive(child) && self.is_alive(parent));
+
+        let mut ancestor = Some(parent);
+        while let Some(entity) = ancestor {
+            assert_ne!(entity, child, "Parent {:?} is a descendant of {:?}", parent, child);
+            ancestor = self.parent(entity);
+        }
+
+        self.remove_parent(child);
+        self.add_component(child, Parent(parent));
+        match self.get_mut::<Children>(parent) {
+            Ok(children) => children.0.push(child),
+            Err(_) => self.add_component(parent, Children(vec![child])),
+        }
+    }
+
+    /// Detach an entity from its parent, turning it into a root.
+    pub fn remove_parent(&mut self, child: Entity) {
+        if let Some(Parent(parent)) = self.remove_component::<Parent>(child) {
+            let empty = match self.get_mut::<Children>(parent) {
+                Ok(children) => {
+                    children.0.retain(|entity| *entity != child);
+                    children.0.is_empty()
+                }
+                Err(_) => false,
+            };
+            if empty {
+                self.remove_component::<Children>(parent);
+            }
+        }
+    }
+
+    pub fn parent(&self, entity: Entity) -> Option<Entity> {
+        self.get::<Parent>(entity).ok().map(Parent::entity)
+    }
+
+    pub fn children(&self, entity: Entity) -> &[Entity] {
+        self.get::<Children>(entity).map(Children::entities).unwrap_or(&[])
+    }
+
+    /// Iterate over `root` and its descendants in breadth-first order with
+    /// their depth relative to `root`.
+    pub fn bfs(&self, root: Entity) -> Bfs {
+        let mut queue = VecDeque::new();
+        if self.is_alive(root) {
+            queue.push_back((root, 0));
+        }
+        Bfs { world: self, queue }
+    }
+
+    /// Compute the world transforms of all entities with a local transform `L`.
+    ///
+    /// Entities without an `L::World` component are skipped. See
+    /// `jobs::propagate_transforms` for the parallel version.
+    pub fn propagate_transforms<L: LocalTransform>(&mut self) {
+        let mut levels = self.transform_levels::<L>();
+        while let Some(level) = levels.next(self) {
+            for entity in level {
+                unsafe {
+                    self.update_world_transform::<L>(*entity);
+                }
+            }
+        }
+    }
+
+    /// Detach a freed entity from the hierarchy.
+    pub(crate) fn detach_hierarchy(&mut self, entity: Entity) {
+        if !self.comp_map.contains_key(&TypeId::of::<Parent>()) {
+            return;
+        }
+
+        self.remove_parent(entity);
+        if let Some(Children(children)) = self.remove_component::<Children>(entity) {
+            for child in children {
+                self.remove_component::<Parent>(child);
+            }
+        }
+    }
+
+    /// Traverse the hierarchy by depth, roots first.
+    ///
+    /// Starts from all roots with `L` or children, as entities with `L` may
+    /// have ancestors without it. Levels contain entities without `L` too,
+    /// which are skipped by `update_world_transform`.
+    pub(crate) fn transform_levels<L: LocalTransform>(&self) -> TransformLevels {
+        let mut level = self
+            .query_ref_filtered::<Entity, (With<L>, Not<Parent>)>()
+            .collect::<Vec<_>>();
+        level.extend(self.query_ref_filtered::<Entity, (With<Children>, Not<Parent>, Not<L>)>());
+        TransformLevels {
+            level,
+            next: Vec::new(),
+            started: false,
+        }
+    }
+
+    /// Compute the world transform of an entity from the world transform of its parent.
+    ///
+    /// The caller has to ensure that the world transform of the entity isn't
+    /// accessed and the one of its parent isn't written concurrently.
+    pub(crate) unsafe fn update_world_transform<L: LocalTransform>(&self, entity: Entity) {
+        let (local, parent) = match self.get_many::<(&L, Option<&Parent>)>(entity) {
+            Ok(components) => components,
+            Err(_) => return,
+        };
+        let parent = parent.and_then(|parent| self.get::<L::World>(parent.0).ok());
+        let transform = local.to_world(parent);
+        if let Ok(world) = self.get_unchecked::<&mut L::World>(entity) {
+            *world = transform;
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Running queries as jobs of a `tanya_jobs` frame.
+//!
+//! The ECS world is stored as resource of a job world. Each component type is
+//! additionally tracked as individual resource of this job world, so jobs
+//! accessing disjoint components run concurrently. Jobs only access the ECS world
+//! itself shared, structural changes require an exclusive access.
+//!
+//! ```ignore
+//! let ecs = frame.access(&mut job_world);
+//! jobs::par_for_each_chunk::<(&Vel, &mut Pos), _>(&mut frame, &ecs, |(vel, pos)| {
+//!     for (pos, vel) in pos.iter_mut().zip(vel) {
+//!         pos.0 += vel.0;
+//!     }
+//! });
+//! ```
+
+use std::sync::Arc;
+use tanya_jobs::frame::{Access as JobAccess, FrameBuilder, WorldHandle};
+use tanya_jobs::futures::task::SpawnExt;
+use tanya_jobs::notify;
+use tanya_jobs::resource::{Read, ResourceTy};
+
+use crate::hierarchy::{Children, LocalTransform, Parent};
+use crate::query::{self, Access, Filter, Query};
+use crate::{Entity, World};
+
+// Number of entities updated per task by `propagate_transforms`.
+const TRANSFORM_BATCH_SIZE: usize = 256;
+
+/// Register the component accesses of the query `Q` for the next spawned job.
+///
+/// Returns a shared handle to the ECS world stored in the job world.
+pub fn access<'a, Q: Query<'a>>(frame: &FrameBuilder, world: &WorldHandle) -> Read<World> {
+    for (ty, access) in query::validate_access::<Q>() {
+        let access = match access {
+            Access::Read => JobAccess::Shared,
+            Access::Write => JobAccess::Exclusive,
+        };
+        frame.access_resource((world.world, ResourceTy(ty)), access);
+    }
+
+    world.query::<World>().read(frame)
+}
+
+/// Spawn a job running `f` for each chunk matching the query `Q`.
+///
+/// The chunks are processed by parallel tasks, the returned receiver is
+/// notified after all chunks have been processed.
+pub fn par_for_each_chunk<Q, Fn>(frame: &mut FrameBuilder, world: &WorldHandle, f: Fn) -> notify::Receiver
+where
+    Q: Query<'static> + 'static,
+    Q::Chunk: Send,
+    Fn: std::ops::Fn(Q::Chunk) + Send + Sync + 'static,
+{
+    par_for_each_chunk_filtered::<Q, (), Fn>(frame, world, f)
+}
+
+/// Filtered variant of `par_for_each_chunk`.
+pub fn par_for_each_chunk_filtered<Q, F, Fn>(
+    frame: &mut FrameBuilder,
+    world: &WorldHandle,
+    f: Fn,
+) -> notify::Receiver
+where
+    Q: Query<'static> + 'static,
+    Q::Chunk: Send,
+    F: Filter + 'static,
+    Fn: std::ops::Fn(Q::Chunk) + Send + Sync + 'static,
+{
+    let ecs = access::<Q>(frame, world);
+    let mut scope = frame.scope();
+    let f = Arc::new(f);
+
+    frame.spawn_job(async move {
+        // The frame guarantees exclusive access to the mutably queried components
+        // until the job finished, which joins all chunk tasks.
+        let world: &'static World = unsafe { &*(&*ecs as *const World) };
+        let chunks = unsafe { world.query_chunks_unchecked::<Q, F>() };
+
+        let mut tasks = Vec::new();
+        for chunk in chunks {
+            let (sender, recv) = notify::channel();
+            let f = f.clone();
+            scope
+                .spawn(async move {
+                    f(chunk);
+                    sender.notify();
+                })
+                .unwrap();
+            tasks.push(recv);
+        }
+
+        // Join all tasks before a panicking one releases the world access.
+        await!(notify::join_all(tasks));
+    })
+}
+
+/// Spawn a job computing the world transforms of all entities with a local transform `L`.
+///
+/// The hierarchy is processed level by level from the roots, entities of the
+/// same depth are updated by parallel tasks.
+pub fn propagate_transforms<L>(frame: &mut FrameBuilder, world: &WorldHandle) -> notify::Receiver
+where
+    L: LocalTransform,
+{
+    let ecs = access::<(&L, &mut L::World, &Parent, &Children)>(frame, world);
+    let mut scope = frame.scope();
+
+    frame.spawn_job(async move {
+        // Tasks of a level only write the transforms of their own entities and
+        // read the transforms of the previous level.
+        let world: &'static World = unsafe { &*(&*ecs as *const World) };
+
+        let mut levels = world.transform_levels::<L>();
+        while let Some(level) = levels.next(world) {
+            // The level buffer isn't modified before all tasks of the level are joined.
+            let level: &'static [Entity] = unsafe { &*(level as *const [Entity]) };
+
+            let mut tasks = Vec::new();
+            for batch in level.chunks(TRANSFORM_BATCH_SIZE) {
+                let (sender, recv) = notify::channel();
+                scope
+                    .spawn(async move {
+                        for entity in batch {
+                            unsafe {
+                                world.update_world_transform::<L>(*entity);
+                            }
+                        }
+                        sender.notify();
+                    })
+                    .unwrap();
+                tasks.push(recv);
+            }
+
+            await!(notify::join_all(tasks));
+        }
+    })
+}
@@ -1,36 +1,107 @@
-#![feature(generic_associated_types)]
-#![feature(vec_resize_with)]
-#![feature(dbg_macro)]
+#![feature(async_await, await_macro, futures_api, generic_associated_types)]
+#![feature(maybe_uninit)]
 
+pub mod command;
 mod free_list;
+pub mod hierarchy;
+pub mod jobs;
+pub mod query;
+pub mod reflect;
+pub mod scene;
+mod sparse;
 
 use std::any::{Any, TypeId};
 use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 use std::marker::PhantomData;
-use std::mem;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::ops::Range;
 
 use self::free_list::Allocator as FreeList;
+use self::reflect::{reflect_slot, ReflectEntry};
+use self::query::{validate_access, Filter, Query, QueryContext, ReadOnlyQuery};
+use self::scene::SerializeEntry;
+use self::sparse::SparseStorage;
 
-const CHUNK_SIZE: usize = 128;
-type Chunk<C> = Box<[C; CHUNK_SIZE]>;
+pub use self::command::CommandBuffer;
+pub use self::hierarchy::{Children, LocalTransform, Parent};
+pub use self::query::{Access, Added, Changed, ChunkIter, Not, QueryIter, With};
+pub use self::reflect::{FieldInfo, Reflect};
+pub use self::scene::{SceneError, SCENE_VERSION};
+pub use self::sparse::{SparseSet, SparseSets};
+
+/// Default chunk size in bytes, see `World::with_chunk_bytes`.
+pub const DEFAULT_CHUNK_BYTES: usize = 16 * 1024;
+
+type Chunk<C> = Box<[MaybeUninit<C>]>;
 
 #[derive(Debug)]
 pub struct ChunkPtr {
     pub ptr: *mut (),
+    // Index of the chunk in the component storage.
+    index: usize,
+    // World tick of the last component insertion into the chunk.
+    added: AtomicUsize,
+    // World tick of the last insertion or mutable access.
+    changed: AtomicUsize,
+}
+
+impl ChunkPtr {
+    fn new(ptr: *mut (), index: usize) -> Self {
+        ChunkPtr {
+            ptr,
+            index,
+            added: AtomicUsize::new(0),
+            changed: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn added(&self) -> Tick {
+        self.added.load(Ordering::Relaxed)
+    }
+
+    pub fn changed(&self) -> Tick {
+        self.changed.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn mark_added(&self, tick: Tick) {
+        self.added.store(tick, Ordering::Relaxed);
+        self.changed.store(tick, Ordering::Relaxed);
+    }
+
+    pub(crate) fn mark_changed(&self, tick: Tick) {
+        self.changed.store(tick, Ordering::Relaxed);
+    }
+
+    /// Keep the ticks of components moved into this chunk from `src`.
+    fn merge_ticks(&self, src: &ChunkPtr) {
+        self.added.store(self.added().max(src.added()), Ordering::Relaxed);
+        self.changed.store(self.changed().max(src.changed()), Ordering::Relaxed);
+    }
 }
 
+// Access to the chunk data is synchronized by the world or the job system.
+unsafe impl Send for ChunkPtr {}
+unsafe impl Sync for ChunkPtr {}
+
 pub type EntityId = u32;
 pub type Generation = u32;
 pub type GroupId = usize;
 pub type ComponentId = usize;
 pub type ChunkId = usize;
 pub type SlotId = usize;
+/// Monotonic world time used for change detection, see `World::increment_tick`.
+pub type Tick = usize;
 
 const GENERATION_INVALID: Generation = Generation::max_value();
 const ENTITY_COMP_ID: ComponentId = 0;
+// Group of entities reserved by command buffers which aren't placed yet.
+const RESERVED_GROUP: GroupId = GroupId::max_value();
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Entity {
     id: EntityId,
     generation: Generation,
@@ -43,6 +114,26 @@ impl Entity {
     };
 }
 
+/// Errors of component lookups by entity.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LookupError {
+    /// The entity has been freed.
+    NoSuchEntity,
+    /// The entity doesn't have all requested components.
+    MissingComponent,
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LookupError::NoSuchEntity => write!(f, "entity doesn't exist"),
+            LookupError::MissingComponent => write!(f, "entity doesn't have the requested component"),
+        }
+    }
+}
+
+impl Error for LookupError {}
+
 #[derive(Copy, Clone, Debug)]
 struct EntityData {
     generation: Generation,
@@ -51,48 +142,111 @@ struct EntityData {
     slot: SlotId,
 }
 
-struct EntityComponent {
-    id: EntityId,
+
+/// Storage layout preferred for a component.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StorageHint {
+    /// Stored in the chunks of the entity group, for components most entities have.
+    Dense,
+    /// Stored outside of the groups, for rare or frequently added and removed components.
+    ///
+    /// Sparse components of created groups are moved into their sparse sets.
+    /// They can't be fetched by group or chunk queries or tracked by change filters.
+    Sparse,
 }
 
-pub trait Component: Clone + Sized + 'static {}
+pub trait Component: Sized + Send + Sync + 'static {
+    const STORAGE: StorageHint = StorageHint::Dense;
+
+    /// Stable name of the component type, e.g. for serialization.
+    ///
+    /// Implemented by `#[derive(Component)]` from the module path and type name.
+    fn type_name() -> &'static str;
 
-trait Storage {
-    fn resize(&mut self, num_chunks: usize);
-    fn alloc_chunks(&mut self, dst: &mut Vec<ChunkPtr>, chunks: Range<usize>);
+    /// Value for default-constructing the component, if supported.
+    fn default_value() -> Option<Self> {
+        None
+    }
+}
+
+/// Component whose value is stored once per chunk, e.g. a material or LOD level.
+///
+/// Entities with different values are stored in different chunks of their group,
+/// see `World::add_shared_component`.
+pub trait SharedComponent: Component + PartialEq + Clone {}
+
+trait Storage: Send + Sync {
+    /// Size of a single component in bytes.
+    fn component_size(&self) -> usize;
+    /// Allocate an uninitialized chunk with `capacity` slots, reusing previously
+    /// released chunks of the same capacity.
+    fn alloc_chunk(&mut self, capacity: usize) -> ChunkPtr;
+    /// Release a chunk with uninitialized slots back to the storage.
+    fn free_chunk(&mut self, chunk: ChunkPtr);
+    /// Move the components of the slots `amount` slots towards the chunk start.
+    ///
+    /// The source slots are uninitialized afterwards.
     fn shift(&self, chunk: &ChunkPtr, slots: Range<usize>, amount: usize);
+    /// Move a single component between slots of two chunks.
+    fn move_slot(&self, src: &ChunkPtr, src_slot: SlotId, dst: &ChunkPtr, dst_slot: SlotId);
+    /// Drop the components of initialized slots.
+    fn drop_slots(&self, chunk: &ChunkPtr, slots: Range<SlotId>);
 }
 
 pub struct ComponentStorage<C> {
     chunks: Vec<Chunk<C>>,
+    // Released chunks by capacity.
+    free_chunks: HashMap<usize, Vec<usize>>,
 }
 
 impl<C> ComponentStorage<C> {
     pub fn new() -> Self {
-        ComponentStorage { chunks: Vec::new() }
+        ComponentStorage {
+            chunks: Vec::new(),
+            free_chunks: HashMap::new(),
+        }
+    }
+
+    fn capacity(&self, chunk: &ChunkPtr) -> usize {
+        self.chunks[chunk.index].len()
     }
 }
 
-impl<C> Storage for ComponentStorage<C> {
-    fn resize(&mut self, num_chunks: usize) {
-        self.chunks
-            .resize_with(num_chunks, || Chunk::new(unsafe { mem::uninitialized() }));
+impl<C: Send + Sync> Storage for ComponentStorage<C> {
+    fn component_size(&self) -> usize {
+        mem::size_of::<C>()
     }
 
-    fn alloc_chunks(&mut self, dst: &mut Vec<ChunkPtr>, chunks: Range<usize>) {
-        for chunk in chunks {
-            dst.push(ChunkPtr {
-                ptr: self.chunks[chunk].as_mut_ptr() as *mut _,
-            })
-        }
+    fn alloc_chunk(&mut self, capacity: usize) -> ChunkPtr {
+        let free = self.free_chunks.get_mut(&capacity).and_then(|chunks| chunks.pop());
+        let index = match free {
+            Some(index) => index,
+            None => {
+                let chunk = (0..capacity)
+                    .map(|_| MaybeUninit::uninitialized())
+                    .collect::<Vec<_>>()
+                    .into_boxed_slice();
+                self.chunks.push(chunk);
+                self.chunks.len() - 1
+            }
+        };
+
+        ChunkPtr::new(self.chunks[index].as_mut_ptr() as *mut _, index)
+    }
+
+    fn free_chunk(&mut self, chunk: ChunkPtr) {
+        debug_assert_eq!(chunk.ptr, self.chunks[chunk.index].as_mut_ptr() as *mut ());
+        let capacity = self.capacity(&chunk);
+        self.free_chunks.entry(capacity).or_insert_with(Vec::new).push(chunk.index);
     }
 
     fn shift(&self, chunk_raw: &ChunkPtr, slots: Range<usize>, amount: usize) {
+        let capacity = self.capacity(chunk_raw);
         assert!(slots.start >= amount);
-        assert!(slots.end <= CHUNK_SIZE);
+        assert!(slots.end <= capacity);
 
         let chunk =
-            unsafe { ::std::slice::from_raw_parts_mut::<C>(chunk_raw.ptr as *mut _, CHUNK_SIZE) };
+            unsafe { ::std::slice::from_raw_parts_mut::<C>(chunk_raw.ptr as *mut _, capacity) };
 
         unsafe {
             ::std::ptr::copy(
@@ -102,15 +256,44 @@ impl<C> Storage for ComponentStorage<C> {
             );
         }
     }
+
+    fn move_slot(&self, src: &ChunkPtr, src_slot: SlotId, dst: &ChunkPtr, dst_slot: SlotId) {
+        assert!(src_slot < self.capacity(src) && dst_slot < self.capacity(dst));
+        unsafe {
+            ::std::ptr::copy_nonoverlapping(
+                (src.ptr as *const C).add(src_slot),
+                (dst.ptr as *mut C).add(dst_slot),
+                1,
+            );
+        }
+    }
+
+    fn drop_slots(&self, chunk: &ChunkPtr, slots: Range<SlotId>) {
+        assert!(slots.end <= self.capacity(chunk));
+        unsafe {
+            let chunk = ::std::slice::from_raw_parts_mut((chunk.ptr as *mut C).add(slots.start), slots.len());
+            ptr::drop_in_place(chunk);
+        }
+    }
 }
 
 pub trait IComponentGroup<'a>: 'static {
     type BuildStream<'a>;
     type Iterator<'a>;
+    type Item<'a>;
 
     fn iter(comp_map: &HashMap<TypeId, ComponentId>, group: &'a GroupStorage) -> Self::Iterator;
     fn define_components(comp_map: &HashMap<TypeId, ComponentId>) -> Vec<ComponentId>;
-    fn fill_slots(
+    /// Convert group values into a stream for creating entities.
+    fn into_stream<Groups: IntoIterator<Item = Self>>(groups: Groups) -> Self::BuildStream
+    where
+        Self: Sized;
+    /// Take ownership of the first `num` components of each stream column.
+    ///
+    /// The components are moved out of the stream by `fill_slots` and `fill_sparse` afterwards.
+    unsafe fn release_stream(stream: &mut Self::BuildStream, num: usize);
+    /// Move dense components of a released stream into uninitialized slots.
+    unsafe fn fill_slots(
         comp_map: &HashMap<TypeId, ComponentId>,
         comp_chunks: &mut HashMap<ComponentId, Vec<ChunkPtr>>,
         chunk: ChunkId,
@@ -119,149 +302,721 @@ pub trait IComponentGroup<'a>: 'static {
         stream_base: usize,
         num: usize,
     );
+    /// Move sparse components of a released stream into the sparse sets of the entities.
+    unsafe fn fill_sparse(sparse: &mut SparseSets, entities: &[Entity], stream: &Self::BuildStream, stream_base: usize);
+
+    /// Read the components of a single slot.
+    ///
+    /// `chunks` contains the chunk pointers of each component of the group in order.
+    unsafe fn fetch(chunks: &[&'a [ChunkPtr]], chunk: ChunkId, slot: SlotId) -> Self::Item;
 }
 
 #[derive(Debug)]
 struct ChunkData {
     pub len: usize,
+    // Value indices of the shared components of the group.
+    pub shared: Vec<usize>,
 }
 
 #[derive(Debug)]
 pub struct GroupStorage {
+    components: Vec<ComponentId>,
+    // Shared components, stored once per chunk instead of per slot.
+    shared: Vec<ComponentId>,
     comp_chunks: HashMap<ComponentId, Vec<ChunkPtr>>,
     chunk_data: Vec<ChunkData>,
     free_chunks: Vec<usize>,
+    // Number of slots per chunk.
+    chunk_capacity: usize,
 }
 
 impl GroupStorage {
     pub fn num_chunks(&self) -> usize {
         self.chunk_data.len()
     }
+    pub fn chunk_capacity(&self) -> usize {
+        self.chunk_capacity
+    }
     pub fn capacity(&self) -> u32 {
-        (self.num_chunks() * CHUNK_SIZE) as u32
+        (self.num_chunks() * self.chunk_capacity) as u32
     }
 }
 
 pub struct World {
     entities: Vec<EntityData>,
+    // Free entity ids, also reserves ids for command buffers.
     entities_free: FreeList,
     group_storages: HashMap<GroupId, GroupStorage>,
     group_map: HashMap<TypeId, GroupId>,
+    group_set_map: HashMap<(Vec<ComponentId>, Vec<ComponentId>), GroupId>,
     comp_storages: HashMap<ComponentId, Box<Storage>>,
     comp_map: HashMap<TypeId, ComponentId>,
+    sparse_sets: HashMap<ComponentId, Box<SparseStorage>>,
+    // Distinct values of each shared component as `Vec<S>`.
+    shared_values: HashMap<ComponentId, Box<Any + Send + Sync>>,
+    reflect: HashMap<ComponentId, ReflectEntry>,
+    serialize: HashMap<ComponentId, SerializeEntry>,
+    tick: Tick,
+    // Byte budget for the chunks of each group.
+    chunk_bytes: usize,
 }
 
 impl World {
     pub fn new() -> Self {
+        Self::with_chunk_bytes(DEFAULT_CHUNK_BYTES)
+    }
+
+    /// Create a world sizing chunks to hold as many entities as fit into `chunk_bytes`.
+    ///
+    /// The capacity of the chunks of a group depends on the summed size of its
+    /// components, chunks hold at least one entity.
+    pub fn with_chunk_bytes(chunk_bytes: usize) -> Self {
         let mut world = World {
             entities: Vec::new(),
             entities_free: FreeList::new(),
             group_storages: HashMap::new(),
             group_map: HashMap::new(),
+            group_set_map: HashMap::new(),
             comp_storages: HashMap::new(),
             comp_map: HashMap::new(),
+            sparse_sets: HashMap::new(),
+            shared_values: HashMap::new(),
+            reflect: HashMap::new(),
+            serialize: HashMap::new(),
+            // Chunk ticks start at 0, so everything counts as changed for `since` 0.
+            tick: 1,
+            chunk_bytes,
         };
 
-        let entity_type_id = TypeId::of::<EntityComponent>();
+        let entity_type_id = TypeId::of::<Entity>();
         let id = ENTITY_COMP_ID;
         world
             .comp_storages
-            .insert(id, Box::new(ComponentStorage::<EntityComponent>::new()));
+            .insert(id, Box::new(ComponentStorage::<Entity>::new()));
         world.comp_map.insert(entity_type_id, id);
 
         world
     }
 
+    /// Define a component with the storage of its `Component::STORAGE` hint.
     pub fn define_component<C: Component>(&mut self) -> ComponentId {
         let type_id = TypeId::of::<C>();
-        *self.comp_map.entry(type_id).or_insert({
-            let id = self.comp_storages.len();
-            let storage = ComponentStorage::<C>::new();
-            self.comp_storages.insert(id, Box::new(storage));
-            id
-        })
+        if let Some(id) = self.comp_map.get(&type_id) {
+            return *id;
+        }
+
+        let id = self.comp_map.len();
+        match C::STORAGE {
+            StorageHint::Dense => {
+                self.comp_storages.insert(id, Box::new(ComponentStorage::<C>::new()));
+            }
+            StorageHint::Sparse => {
+                self.sparse_sets.insert(id, Box::new(SparseSet::<C>::new()));
+            }
+        }
+        self.comp_map.insert(type_id, id);
+        id
     }
 
     pub fn define_group<'a, G: IComponentGroup<'a>>(&mut self) -> GroupId {
         let type_id = TypeId::of::<G>();
-        *self.group_map.entry(type_id).or_insert({
-            let id = self.group_storages.len();
-            let components = G::define_components(&self.comp_map);
-            let mut storage = GroupStorage {
-                comp_chunks: HashMap::new(),
-                chunk_data: Vec::new(),
-                free_chunks: Vec::new(),
-            };
-            for comp in components {
-                storage.comp_chunks.insert(comp, Vec::new());
-            }
-            self.group_storages.insert(id, storage);
-            id
-        })
+        if let Some(id) = self.group_map.get(&type_id) {
+            return *id;
+        }
+
+        // Sparse components of the group are stored in their sparse sets instead.
+        let mut components = G::define_components(&self.comp_map);
+        components.retain(|id| !self.sparse_sets.contains_key(id));
+        let id = self.define_group_components(components, Vec::new());
+        self.group_map.insert(type_id, id);
+        id
     }
 
+    /// Define the group for a set of components, including the entity component,
+    /// and sorted shared components.
+    fn define_group_components(&mut self, mut components: Vec<ComponentId>, shared: Vec<ComponentId>) -> GroupId {
+        components.sort();
+        components.dedup();
+        let key = (components, shared);
+        if let Some(id) = self.group_set_map.get(&key) {
+            return *id;
+        }
+
+        let (components, shared) = key;
+        assert!(
+            components.iter().all(|id| self.comp_storages.contains_key(id)),
+            "Sparse components can't be stored in groups"
+        );
+        let entity_bytes = components
+            .iter()
+            .map(|id| self.comp_storages[id].component_size())
+            .sum::<usize>();
+        let id = self.group_storages.len();
+        let mut storage = GroupStorage {
+            components: components.clone(),
+            shared: shared.clone(),
+            comp_chunks: HashMap::new(),
+            chunk_data: Vec::new(),
+            free_chunks: Vec::new(),
+            chunk_capacity: (self.chunk_bytes / entity_bytes.max(1)).max(1),
+        };
+        for comp in &components {
+            storage.comp_chunks.insert(*comp, Vec::new());
+        }
+        self.group_storages.insert(id, storage);
+        self.group_set_map.insert((components, shared), id);
+        id
+    }
+
+    /// Free entities and drop their components.
+    ///
+    /// Children of freed entities become roots of the hierarchy. Handles of
+    /// entities which aren't alive anymore are skipped.
     pub fn free_entities(&mut self, entities: &[Entity]) {
         for entity in entities {
+            if !self.is_alive(*entity) {
+                continue;
+            }
+            self.detach_hierarchy(*entity);
+
             let entity_id = entity.id;
             self.entities_free.deallocate(entity_id..entity_id + 1);
             let entity_data = self.entities[entity_id as usize];
-            assert_eq!(entity.generation, entity_data.generation);
 
             self.entities[entity_id as usize].generation += 1;
+            for set in self.sparse_sets.values_mut() {
+                set.free(entity_id);
+            }
 
-            let group_id = entity_data.group;
-            let group = if let Some(group) = self.group_storages.get_mut(&group_id) {
-                group
-            } else {
+            if !self.group_storages.contains_key(&entity_data.group) {
                 continue;
-            };
+            }
 
-            let used_chunk_slots = group.chunk_data[entity_data.chunk].len;
-            debug_assert!(used_chunk_slots > 0);
-            group.chunk_data[entity_data.chunk].len -= 1;
-
-            let src_slot = used_chunk_slots - 1;
-            let reposition = src_slot != entity_data.slot;
-            if reposition {
-                let src_entity_id = {
-                    let entity_comp = &group.comp_chunks[&ENTITY_COMP_ID];
-                    let entity_chunk_raw = &entity_comp[entity_data.chunk];
-                    let entity_chunk = unsafe {
-                        ::std::slice::from_raw_parts(
-                            entity_chunk_raw.ptr as *mut EntityComponent,
-                            CHUNK_SIZE,
-                        )
-                    };
+            for (comp_id, chunks) in &self.group_storages[&entity_data.group].comp_chunks {
+                let slots = entity_data.slot..entity_data.slot + 1;
+                self.comp_storages[comp_id].drop_slots(&chunks[entity_data.chunk], slots);
+            }
+            self.remove_slot(entity_data.group, entity_data.chunk, entity_data.slot);
+        }
+    }
+
+    /// Remove a slot from a group chunk by moving the last slot of the chunk into it.
+    ///
+    /// The components of the removed slot are not dropped.
+    fn remove_slot(&mut self, group_id: GroupId, chunk: ChunkId, slot: SlotId) {
+        let group = self.group_storages.get_mut(&group_id).unwrap();
 
-                    dbg!(entity_chunk[src_slot].id)
+        let used_chunk_slots = group.chunk_data[chunk].len;
+        debug_assert!(used_chunk_slots > 0);
+        group.chunk_data[chunk].len -= 1;
+        if used_chunk_slots == group.chunk_capacity {
+            group.free_chunks.push(chunk);
+        }
+
+        let src_slot = used_chunk_slots - 1;
+        let reposition = src_slot != slot;
+        if reposition {
+            let src_entity_id = {
+                let entity_comp = &group.comp_chunks[&ENTITY_COMP_ID];
+                let entity_chunk_raw = &entity_comp[chunk];
+                let entity_chunk = unsafe {
+                    ::std::slice::from_raw_parts(entity_chunk_raw.ptr as *mut Entity, used_chunk_slots)
                 };
 
-                println!("{:#?}", &self.entities);
-                self.entities[src_entity_id as usize].slot = entity_data.slot;
+                entity_chunk[src_slot].id
+            };
+
+            self.entities[src_entity_id as usize].slot = slot;
+            for (comp_id, chunks) in &group.comp_chunks {
+                let chunk = &chunks[chunk];
+                self.comp_storages[&comp_id].shift(chunk, src_slot..src_slot + 1, src_slot - slot);
+            }
+        }
+
+        if used_chunk_slots == 1 {
+            self.release_chunk(group_id, chunk);
+        }
+    }
+
+    /// Release an empty chunk of a group back to the component storages.
+    ///
+    /// The last chunk of the group takes the id of the released chunk.
+    fn release_chunk(&mut self, group_id: GroupId, chunk: ChunkId) {
+        let group = self.group_storages.get_mut(&group_id).unwrap();
+        debug_assert_eq!(group.chunk_data[chunk].len, 0);
+
+        let last_chunk = group.num_chunks() - 1;
+        group.free_chunks.retain(|c| *c != chunk);
+        for free_chunk in &mut group.free_chunks {
+            if *free_chunk == last_chunk {
+                *free_chunk = chunk;
+            }
+        }
+
+        group.chunk_data.swap_remove(chunk);
+        for (comp_id, chunks) in &mut group.comp_chunks {
+            let chunk_raw = chunks.swap_remove(chunk);
+            self.comp_storages.get_mut(comp_id).unwrap().free_chunk(chunk_raw);
+        }
+
+        if chunk != last_chunk {
+            let len = group.chunk_data[chunk].len;
+            let entity_chunk = unsafe {
+                ::std::slice::from_raw_parts(
+                    group.comp_chunks[&ENTITY_COMP_ID][chunk].ptr as *const Entity,
+                    len,
+                )
+            };
+            for entity in entity_chunk {
+                self.entities[entity.id as usize].chunk = chunk;
+            }
+        }
+    }
+
+    /// Repack the partially filled chunks of all groups, releasing emptied chunks.
+    pub fn compact(&mut self) {
+        self.compact_incremental(usize::max_value());
+    }
+
+    /// Incremental variant of `compact`, moving at most `budget` entities per call.
+    ///
+    /// Returns `true` if all groups are compacted.
+    pub fn compact_incremental(&mut self, budget: usize) -> bool {
+        let mut groups = self.group_storages.keys().cloned().collect::<Vec<_>>();
+        groups.sort();
+
+        let mut budget = budget;
+        for group in groups {
+            if !self.compact_group(group, &mut budget) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Move entities from the emptiest partially filled chunk into the fullest one
+    /// until at most one chunk of the group is partially filled or the budget is spent.
+    ///
+    /// Returns `true` if the group is compacted.
+    fn compact_group(&mut self, group_id: GroupId, budget: &mut usize) -> bool {
+        loop {
+            let (src, dst) = {
+                let group = &self.group_storages[&group_id];
+                if group.free_chunks.len() < 2 {
+                    return true;
+                }
+
+                // Only chunks with the same shared component values can be merged.
+                let len = |chunk: &ChunkId| group.chunk_data[*chunk].len;
+                let shared = |chunk: &ChunkId| &group.chunk_data[*chunk].shared;
+                let mut sources = group.free_chunks.clone();
+                sources.sort_by_key(|c| len(c));
+                let pair = sources.iter().find_map(|src| {
+                    group
+                        .free_chunks
+                        .iter()
+                        .filter(|c| *c != src && shared(c) == shared(src))
+                        .max_by_key(|c| len(c))
+                        .map(|dst| (*src, *dst))
+                });
+                match pair {
+                    Some(_) if *budget == 0 => return false,
+                    Some(pair) => pair,
+                    None => return true,
+                }
+            };
+
+            let group = self.group_storages.get_mut(&group_id).unwrap();
+            let capacity = group.chunk_capacity;
+            while *budget > 0 && group.chunk_data[src].len > 0 && group.chunk_data[dst].len < capacity {
+                let src_slot = group.chunk_data[src].len - 1;
+                let dst_slot = group.chunk_data[dst].len;
                 for (comp_id, chunks) in &group.comp_chunks {
-                    let chunk = &chunks[entity_data.chunk];
-                    self.comp_storages[&comp_id].shift(
-                        chunk,
-                        src_slot..src_slot + 1,
-                        src_slot - entity_data.slot,
-                    );
+                    self.comp_storages[comp_id].move_slot(&chunks[src], src_slot, &chunks[dst], dst_slot);
+                    chunks[dst].merge_ticks(&chunks[src]);
+                }
+                group.chunk_data[src].len -= 1;
+                group.chunk_data[dst].len += 1;
+
+                let entity = unsafe { *(group.comp_chunks[&ENTITY_COMP_ID][dst].ptr as *const Entity).add(dst_slot) };
+                let entity_data = &mut self.entities[entity.id as usize];
+                entity_data.chunk = dst;
+                entity_data.slot = dst_slot;
+
+                *budget -= 1;
+            }
+
+            if group.chunk_data[dst].len == capacity {
+                group.free_chunks.retain(|c| *c != dst);
+            }
+            if group.chunk_data[src].len == 0 {
+                self.release_chunk(group_id, src);
+            }
+        }
+    }
+
+    /// Add a component to an entity, moving it into the group with the extended component set.
+    ///
+    /// Replaces the current value if the entity already has the component.
+    pub fn add_component<C: Component>(&mut self, entity: Entity, value: C) {
+        let comp_id = self.define_component::<C>();
+        let entity_data = self.entities[entity.id as usize];
+        assert_eq!(entity.generation, entity_data.generation);
+
+        if let Some(set) = self.sparse_set_mut::<C>(comp_id) {
+            set.insert(entity.id, value);
+            return;
+        }
+
+        let (components, shared) = {
+            let group = &self.group_storages[&entity_data.group];
+            if let Some(chunks) = group.comp_chunks.get(&comp_id) {
+                unsafe {
+                    *(chunks[entity_data.chunk].ptr as *mut C).add(entity_data.slot) = value;
                 }
+                chunks[entity_data.chunk].mark_changed(self.tick);
+                return;
             }
 
-            if used_chunk_slots == 1 {
-                // free chunk
-                // TODO
+            let mut components = group.components.clone();
+            components.push(comp_id);
+            (components, self.chunk_shared(entity_data))
+        };
+
+        let (group, chunk, slot) = self.migrate(entity, components, shared);
+        let chunks = &self.group_storages[&group].comp_chunks[&comp_id];
+        unsafe {
+            ::std::ptr::write((chunks[chunk].ptr as *mut C).add(slot), value);
+        }
+        chunks[chunk].mark_added(self.tick);
+    }
+
+    /// Add a component to each entity, see `add_component`.
+    ///
+    /// Entities sharing a chunk are moved into the extended group together.
+    pub fn add_components<C, I>(&mut self, entities: &[Entity], values: I)
+    where
+        C: Component,
+        I: IntoIterator<Item = C>,
+    {
+        let comp_id = self.define_component::<C>();
+        let mut values = values.into_iter();
+        let mut pending = Vec::new();
+        for entity in entities {
+            let value = values.next().expect("Less component values than entities");
+            let entity_data = self.entities[entity.id as usize];
+            assert_eq!(entity.generation, entity_data.generation);
+
+            let in_place = self.sparse_sets.contains_key(&comp_id)
+                || self.group_storages[&entity_data.group].comp_chunks.contains_key(&comp_id);
+            if in_place {
+                self.add_component(*entity, value);
             } else {
-                //
+                pending.push(((entity_data.group, entity_data.chunk), (*entity, value)));
+            }
+        }
+
+        // The last value wins for entities passed multiple times.
+        pending.sort_by_key(|(chunk, (entity, _))| (*chunk, entity.id));
+        pending.dedup_by(|(_, (a, a_value)), (_, (b, b_value))| {
+            if a == b {
+                ::std::mem::swap(a_value, b_value);
+            }
+            a == b
+        });
+
+        for batch in chunk_batches(pending) {
+            let (batch, values): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+            let (components, shared) = {
+                let entity_data = self.entities[batch[0].id as usize];
+                let mut components = self.group_storages[&entity_data.group].components.clone();
+                components.push(comp_id);
+                (components, self.chunk_shared(entity_data))
+            };
+
+            let group = self.migrate_batch(&batch, components, shared);
+            let chunks = &self.group_storages[&group].comp_chunks[&comp_id];
+            for (entity, value) in batch.iter().zip(values) {
+                let entity_data = self.entities[entity.id as usize];
+                unsafe {
+                    ::std::ptr::write((chunks[entity_data.chunk].ptr as *mut C).add(entity_data.slot), value);
+                }
+                chunks[entity_data.chunk].mark_added(self.tick);
+            }
+        }
+    }
+
+    /// Remove a component from an entity, moving it into the group with the reduced component set.
+    ///
+    /// Returns the removed component or `None` if the entity didn't have the component.
+    pub fn remove_component<C: Component>(&mut self, entity: Entity) -> Option<C> {
+        let comp_id = *self.comp_map.get(&TypeId::of::<C>())?;
+        let entity_data = self.entities[entity.id as usize];
+        assert_eq!(entity.generation, entity_data.generation);
+
+        if let Some(set) = self.sparse_set_mut::<C>(comp_id) {
+            return set.remove(entity.id);
+        }
+
+        let (value, components, shared) = {
+            let group = &self.group_storages[&entity_data.group];
+            let chunks = group.comp_chunks.get(&comp_id)?;
+            let value = unsafe {
+                ::std::ptr::read((chunks[entity_data.chunk].ptr as *const C).add(entity_data.slot))
+            };
+            let components = group
+                .components
+                .iter()
+                .cloned()
+                .filter(|id| *id != comp_id)
+                .collect();
+            (value, components, self.chunk_shared(entity_data))
+        };
+
+        self.migrate(entity, components, shared);
+        Some(value)
+    }
+
+    /// Remove a component from each entity and drop it, see `remove_component`.
+    ///
+    /// Entities sharing a chunk are moved into the reduced group together.
+    pub fn remove_components<C: Component>(&mut self, entities: &[Entity]) {
+        let comp_id = match self.comp_map.get(&TypeId::of::<C>()) {
+            Some(id) => *id,
+            None => return,
+        };
+
+        if self.sparse_sets.contains_key(&comp_id) {
+            for entity in entities {
+                self.remove_component::<C>(*entity);
+            }
+            return;
+        }
+
+        let mut pending = Vec::new();
+        for entity in entities {
+            let entity_data = self.entities[entity.id as usize];
+            assert_eq!(entity.generation, entity_data.generation);
+            if self.group_storages[&entity_data.group].comp_chunks.contains_key(&comp_id) {
+                pending.push(((entity_data.group, entity_data.chunk), *entity));
+            }
+        }
+        pending.sort_by_key(|(chunk, entity)| (*chunk, entity.id));
+        pending.dedup();
+
+        for batch in chunk_batches(pending) {
+            let (components, shared) = {
+                let entity_data = self.entities[batch[0].id as usize];
+                let group = &self.group_storages[&entity_data.group];
+                let chunk = &group.comp_chunks[&comp_id][entity_data.chunk];
+                for entity in &batch {
+                    let slot = self.entities[entity.id as usize].slot;
+                    self.comp_storages[&comp_id].drop_slots(chunk, slot..slot + 1);
+                }
+
+                let components = group
+                    .components
+                    .iter()
+                    .cloned()
+                    .filter(|id| *id != comp_id)
+                    .collect();
+                (components, self.chunk_shared(entity_data))
+            };
+
+            self.migrate_batch(&batch, components, shared);
+        }
+    }
+
+    fn sparse_set_mut<C: Component>(&mut self, comp_id: ComponentId) -> Option<&mut SparseSet<C>> {
+        self.sparse_sets
+            .get_mut(&comp_id)
+            .and_then(|set| set.as_any_mut().downcast_mut())
+    }
+
+    /// Set the shared component `S` of an entity, moving it into a chunk with this value.
+    pub fn add_shared_component<S: SharedComponent>(&mut self, entity: Entity, value: S) {
+        let comp_id = self.define_component::<S>();
+        let index = self.shared_index(comp_id, value);
+        let entity_data = self.entities[entity.id as usize];
+        assert_eq!(entity.generation, entity_data.generation);
+
+        let mut shared = self.chunk_shared(entity_data);
+        match shared.iter_mut().find(|(id, _)| *id == comp_id) {
+            Some((_, value)) if *value == index => return,
+            Some((_, value)) => *value = index,
+            None => {
+                shared.push((comp_id, index));
+                shared.sort();
+            }
+        }
+
+        let components = self.group_storages[&entity_data.group].components.clone();
+        self.migrate(entity, components, shared);
+    }
+
+    /// Remove the shared component `S` from an entity.
+    ///
+    /// Returns the value or `None` if the entity didn't have the component.
+    pub fn remove_shared_component<S: SharedComponent>(&mut self, entity: Entity) -> Option<S> {
+        let comp_id = *self.comp_map.get(&TypeId::of::<S>())?;
+        let entity_data = self.entities[entity.id as usize];
+        assert_eq!(entity.generation, entity_data.generation);
+
+        let mut shared = self.chunk_shared(entity_data);
+        let pos = shared.iter().position(|(id, _)| *id == comp_id)?;
+        let (_, index) = shared.remove(pos);
+        let value = self.shared_values::<S>()[index].clone();
+
+        let components = self.group_storages[&entity_data.group].components.clone();
+        self.migrate(entity, components, shared);
+        Some(value)
+    }
+
+    pub fn get_shared<S: SharedComponent>(&self, entity: Entity) -> Result<&S, LookupError> {
+        let entity_data = self.entity_data(entity)?;
+        let comp_id = *self
+            .comp_map
+            .get(&TypeId::of::<S>())
+            .ok_or(LookupError::MissingComponent)?;
+        let index = self
+            .chunk_shared(entity_data)
+            .into_iter()
+            .find(|(id, _)| *id == comp_id)
+            .map(|(_, index)| index)
+            .ok_or(LookupError::MissingComponent)?;
+        Ok(&self.shared_values::<S>()[index])
+    }
+
+    /// Distinct values of the shared component `S` which have been used so far.
+    pub fn shared_values<S: SharedComponent>(&self) -> &[S] {
+        self.comp_map
+            .get(&TypeId::of::<S>())
+            .and_then(|id| self.shared_values.get(id))
+            .and_then(|values| values.downcast_ref::<Vec<S>>())
+            .map(|values| &values[..])
+            .unwrap_or(&[])
+    }
+
+    /// Index of a shared component value, values are never removed.
+    fn shared_index<S: SharedComponent>(&mut self, comp_id: ComponentId, value: S) -> usize {
+        let values = self
+            .shared_values
+            .entry(comp_id)
+            .or_insert_with(|| Box::new(Vec::<S>::new()))
+            .downcast_mut::<Vec<S>>()
+            .unwrap();
+        match values.iter().position(|v| *v == value) {
+            Some(index) => index,
+            None => {
+                values.push(value);
+                values.len() - 1
+            }
+        }
+    }
+
+    /// Shared component ids and value indices of the chunk of an entity.
+    fn chunk_shared(&self, entity_data: EntityData) -> Vec<(ComponentId, usize)> {
+        let group = &self.group_storages[&entity_data.group];
+        let values = &group.chunk_data[entity_data.chunk].shared;
+        group.shared.iter().cloned().zip(values.iter().cloned()).collect()
+    }
+
+    /// Move an entity into the group with the given components and into a chunk
+    /// with the given sorted shared component values, see `migrate_batch`.
+    fn migrate(
+        &mut self,
+        entity: Entity,
+        components: Vec<ComponentId>,
+        shared: Vec<(ComponentId, usize)>,
+    ) -> (GroupId, ChunkId, SlotId) {
+        self.migrate_batch(&[entity], components, shared);
+        let dst = self.entities[entity.id as usize];
+        (dst.group, dst.chunk, dst.slot)
+    }
+
+    /// Move entities of a single chunk into the group with the given components
+    /// and into chunks with the given sorted shared component values.
+    ///
+    /// Destination slots are allocated in bulk. Components contained in both groups
+    /// are moved, components only contained in the current group are left behind
+    /// without being dropped.
+    fn migrate_batch(
+        &mut self,
+        entities: &[Entity],
+        components: Vec<ComponentId>,
+        shared: Vec<(ComponentId, usize)>,
+    ) -> GroupId {
+        let src = self.entities[entities[0].id as usize];
+        let (shared, values): (Vec<_>, Vec<_>) = shared.into_iter().unzip();
+        let dst_group = self.define_group_components(components, shared);
+
+        let mut src_slots = Vec::with_capacity(entities.len());
+        while src_slots.len() < entities.len() {
+            let num = entities.len() - src_slots.len();
+            let (dst_chunk, dst_slots) = self.alloc_group_slots(dst_group, num as _, &values);
+
+            let src_storage = &self.group_storages[&src.group];
+            let dst_storage = &self.group_storages[&dst_group];
+            for (entity, dst_slot) in entities[src_slots.len()..].iter().zip(dst_slots) {
+                let entity_data = &mut self.entities[entity.id as usize];
+                debug_assert_eq!((entity_data.group, entity_data.chunk), (src.group, src.chunk));
+                for (comp_id, src_chunks) in &src_storage.comp_chunks {
+                    if let Some(dst_chunks) = dst_storage.comp_chunks.get(comp_id) {
+                        self.comp_storages[comp_id].move_slot(
+                            &src_chunks[src.chunk],
+                            entity_data.slot,
+                            &dst_chunks[dst_chunk],
+                            dst_slot,
+                        );
+                    }
+                }
+
+                src_slots.push(entity_data.slot);
+                entity_data.group = dst_group;
+                entity_data.chunk = dst_chunk;
+                entity_data.slot = dst_slot;
+            }
+
+            for (comp_id, src_chunks) in &src_storage.comp_chunks {
+                if let Some(dst_chunks) = dst_storage.comp_chunks.get(comp_id) {
+                    dst_chunks[dst_chunk].merge_ticks(&src_chunks[src.chunk]);
+                }
             }
         }
+
+        // Remove from the back, the last slot of the chunk is then never one of the
+        // moved slots. Releasing the source chunk may move a destination chunk within
+        // the same group, which updates the entity data.
+        src_slots.sort();
+        for slot in src_slots.into_iter().rev() {
+            self.remove_slot(src.group, src.chunk, slot);
+        }
+
+        dst_group
+    }
+
+    /// Create an entity for each group value, e.g. from a derived `ComponentGroup` struct.
+    pub fn spawn_batch<'a, G, I>(&mut self, groups: I) -> Vec<Entity>
+    where
+        G: IComponentGroup<'a>,
+        I: IntoIterator<Item = G>,
+    {
+        let groups = groups.into_iter().collect::<Vec<_>>();
+        let mut entities = vec![Entity::INVALID; groups.len()];
+        self.create_entities::<G>(&mut entities, G::into_stream(groups));
+        entities
     }
 
     pub fn create_entities<'a, G: IComponentGroup<'a>>(
         &mut self,
         entities: &mut [Entity],
-        stream: G::BuildStream,
+        mut stream: G::BuildStream,
     ) {
+        unsafe {
+            G::release_stream(&mut stream, entities.len());
+        }
+
         let group_id = self.define_group::<G>();
         let mut num_entities = entities.len();
         let mut cur_entity = 0;
@@ -273,9 +1028,11 @@ impl World {
                 num_entities -= num_allocated as usize;
                 slots
             } else {
+                let start = self.entities_free.reserve(num_entities).start as usize;
+                self.place_reserved_range(start);
                 self.entities_free.append(num_entities as _);
                 self.entities.resize(
-                    self.entities.len() + num_entities,
+                    start + num_entities,
                     EntityData {
                         generation: 0,
                         group: 0,
@@ -285,8 +1042,30 @@ impl World {
                 );
                 continue;
             };
-            let num_slots = entity_slots.end - entity_slots.start;
-            let (chunk, chunk_slots) = self.alloc_group_slots::<G>(group_id, num_slots as _);
+            let num_allocated = (entity_slots.end - entity_slots.start) as usize;
+            self.place_entities::<G>(group_id, entity_slots, &stream, entities, cur_entity);
+            cur_entity += num_allocated;
+        }
+
+        unsafe {
+            G::fill_sparse(&mut SparseSets::new(&self.comp_map, &mut self.sparse_sets), entities, &stream, 0);
+        }
+    }
+
+    /// Place the entities `ids` into the group with the stream components starting at `cur_entity`.
+    fn place_entities<'a, G: IComponentGroup<'a>>(
+        &mut self,
+        group_id: GroupId,
+        mut ids: Range<EntityId>,
+        stream: &G::BuildStream,
+        entities: &mut [Entity],
+        mut cur_entity: usize,
+    ) {
+        while ids.start < ids.end {
+            let num_ids = ids.end - ids.start;
+            let (chunk, chunk_slots) =
+                self.alloc_group_slots(group_id, num_ids as _, &[]);
+            let num_slots = chunk_slots.end - chunk_slots.start;
 
             {
                 let entity_comp = unsafe {
@@ -297,230 +1076,669 @@ impl World {
                             .comp_chunks
                             .get_mut(&ENTITY_COMP_ID)
                             .unwrap()[chunk]
-                            .ptr as *mut EntityComponent,
-                        CHUNK_SIZE,
+                            .ptr as *mut Entity,
+                        chunk_slots.end,
                     )
                 };
 
                 for slot in 0..num_slots {
                     let entity = cur_entity + slot;
 
-                    let entity_data = &mut self.entities[(entity_slots.start + slot) as usize];
-                    let entity_id = (entity_slots.start + slot) as EntityId;
-                    let slot_id = chunk_slots.start + slot as usize;
+                    let entity_id = ids.start + slot as EntityId;
+                    let entity_data = &mut self.entities[entity_id as usize];
+                    let slot_id = chunk_slots.start + slot;
+                    let handle = Entity {
+                        id: entity_id,
+                        generation: entity_data.generation,
+                    };
 
-                    entity_comp[slot_id] = EntityComponent { id: entity_id };
+                    entity_comp[slot_id] = handle;
                     entity_data.group = group_id;
                     entity_data.chunk = chunk;
                     entity_data.slot = slot_id;
-                    entities[entity as usize] = Entity {
-                        id: entity_id,
-                        generation: entity_data.generation,
-                    };
+                    entities[entity] = handle;
                 }
             }
 
-            G::fill_slots(
-                &self.comp_map,
-                &mut self.group_storages.get_mut(&group_id).unwrap().comp_chunks,
-                chunk,
-                chunk_slots.start as _,
-                &stream,
-                cur_entity as _,
-                num_slots as _,
-            );
+            unsafe {
+                G::fill_slots(
+                    &self.comp_map,
+                    &mut self.group_storages.get_mut(&group_id).unwrap().comp_chunks,
+                    chunk,
+                    chunk_slots.start as _,
+                    stream,
+                    cur_entity as _,
+                    num_slots as _,
+                );
+            }
+            self.mark_added(group_id, chunk);
 
             cur_entity += num_slots;
+            ids.start += num_slots as EntityId;
         }
     }
 
-    fn alloc_group_slots<'a, G: IComponentGroup<'a>>(
-        &mut self,
-        group_id: GroupId,
-        num: u32,
-    ) -> (ChunkId, Range<SlotId>) {
-        let group = self.group_storages.get_mut(&group_id).unwrap();
-        match group.free_chunks.pop() {
-            Some(chunk) => {
-                let start_slot = group.chunk_data[chunk].len;
-                let num_free = CHUNK_SIZE - start_slot;
-                let num_alloc = num_free.min(num as _);
-                group.chunk_data[chunk].len += num_alloc;
-                if num_alloc < num_free {
-                    group.free_chunks.push(chunk);
-                }
+    /// Create a command buffer for recording structural changes, e.g. from jobs.
+    pub fn commands(&self) -> CommandBuffer {
+        CommandBuffer::new(self.entities_free.reserver())
+    }
 
-                (chunk, start_slot..start_slot + num_alloc)
-            }
-            None => {
-                let required_chunks = (num + CHUNK_SIZE as u32 - 1) / CHUNK_SIZE as u32;
-                let cur_chunks = group.num_chunks();
-                let components = G::define_components(&self.comp_map);
-                for component in components {
-                    let comp_storage = self.comp_storages.get_mut(&component).unwrap();
-                    comp_storage.resize(required_chunks as _);
-                    let num_chunks = group.num_chunks();
-                    comp_storage.alloc_chunks(
-                        group.comp_chunks.get_mut(&component).unwrap(),
-                        num_chunks as usize..required_chunks as usize,
-                    );
-                }
+    /// Apply the commands of a buffer in recording order.
+    ///
+    /// Buffers recorded in parallel should be applied in a fixed order to keep
+    /// the results deterministic.
+    pub fn apply(&mut self, commands: CommandBuffer) {
+        let end = self.entities_free.reserved_end();
+        self.place_reserved_range(end as usize);
+        commands.apply(self);
+    }
 
-                for _ in cur_chunks..cur_chunks + required_chunks as usize {
-                    group.chunk_data.push(ChunkData { len: 0 });
-                }
+    /// Add all entities reserved by command buffers below `end` to the entity table.
+    ///
+    /// Reserved entities aren't alive until their buffer places them. The ids
+    /// released by buffers dropped without being applied are freed.
+    fn place_reserved_range(&mut self, end: usize) {
+        let start = self.entities.len();
+        if end > start {
+            self.entities_free.append_allocated((end - start) as _);
+            self.entities.resize(
+                end,
+                EntityData {
+                    generation: 0,
+                    group: RESERVED_GROUP,
+                    chunk: 0,
+                    slot: 0,
+                },
+            );
+        }
 
-                let num_allocated = num.min(CHUNK_SIZE as _);
-                if num < CHUNK_SIZE as u32 {
-                    group.free_chunks.push(cur_chunks);
-                }
-                group.chunk_data[cur_chunks].len = num_allocated as _;
+        for ids in self.entities_free.take_released(self.entities.len() as _) {
+            for id in ids.clone() {
+                self.entities[id as usize].generation += 1;
+            }
+            self.entities_free.deallocate(ids);
+        }
+    }
 
-                for chunk in ((cur_chunks + 1)..(cur_chunks + required_chunks as usize)).rev() {
+    /// Place reserved entities into the group `G` with the components of the stream.
+    fn place_reserved<'a, G: IComponentGroup<'a>>(&mut self, entities: &mut [Entity], mut stream: G::BuildStream) {
+        unsafe {
+            G::release_stream(&mut stream, entities.len());
+        }
+        if entities.is_empty() {
+            return;
+        }
+
+        // Entities of a buffer command are reserved at once.
+        let start = entities[0].id;
+        let ids = start..start + entities.len() as EntityId;
+        debug_assert!(ids
+            .clone()
+            .all(|id| self.entities[id as usize].group == RESERVED_GROUP));
+
+        let group_id = self.define_group::<G>();
+        self.place_entities::<G>(group_id, ids, &stream, entities, 0);
+
+        unsafe {
+            G::fill_sparse(&mut SparseSets::new(&self.comp_map, &mut self.sparse_sets), entities, &stream, 0);
+        }
+    }
+
+    /// Mark all components of a group chunk as added at the current tick.
+    fn mark_added(&self, group_id: GroupId, chunk: ChunkId) {
+        for chunks in self.group_storages[&group_id].comp_chunks.values() {
+            chunks[chunk].mark_added(self.tick);
+        }
+    }
+
+    /// Allocate up to `num` slots in a chunk with the given shared component values.
+    fn alloc_group_slots(&mut self, group_id: GroupId, num: u32, shared: &[usize]) -> (ChunkId, Range<SlotId>) {
+        let group = self.group_storages.get_mut(&group_id).unwrap();
+        let capacity = group.chunk_capacity;
+        let free_chunk = {
+            let chunk_data = &group.chunk_data;
+            group
+                .free_chunks
+                .iter()
+                .rposition(|chunk| chunk_data[*chunk].shared[..] == *shared)
+        };
+        match free_chunk.map(|i| group.free_chunks.remove(i)) {
+            Some(chunk) => {
+                let start_slot = group.chunk_data[chunk].len;
+                let num_free = capacity - start_slot;
+                let num_alloc = num_free.min(num as _);
+                group.chunk_data[chunk].len += num_alloc;
+                if num_alloc < num_free {
                     group.free_chunks.push(chunk);
                 }
 
-                (cur_chunks, 0..num_allocated as usize)
+                (chunk, start_slot..start_slot + num_alloc)
+            }
+            None => {
+                let required_chunks = (num as usize + capacity - 1) / capacity;
+                let cur_chunks = group.num_chunks();
+                for component in &group.components {
+                    let comp_storage = self.comp_storages.get_mut(component).unwrap();
+                    let chunks = group.comp_chunks.get_mut(component).unwrap();
+                    for _ in 0..required_chunks {
+                        chunks.push(comp_storage.alloc_chunk(capacity));
+                    }
+                }
+
+                for _ in cur_chunks..cur_chunks + required_chunks {
+                    group.chunk_data.push(ChunkData {
+                        len: 0,
+                        shared: shared.to_vec(),
+                    });
+                }
+
+                let num_allocated = (num as usize).min(capacity);
+                if num_allocated < capacity {
+                    group.free_chunks.push(cur_chunks);
+                }
+                group.chunk_data[cur_chunks].len = num_allocated;
+
+                for chunk in ((cur_chunks + 1)..(cur_chunks + required_chunks)).rev() {
+                    group.free_chunks.push(chunk);
+                }
+
+                (cur_chunks, 0..num_allocated)
             }
         }
     }
 
+    /// Group storing exactly the components of `G`.
+    ///
+    /// Groups created by adding or removing components aren't registered by type.
+    fn group_id<'a, G: IComponentGroup<'a>>(&self) -> GroupId {
+        if let Some(id) = self.group_map.get(&TypeId::of::<G>()) {
+            return *id;
+        }
+
+        let mut components = G::define_components(&self.comp_map);
+        components.sort();
+        components.dedup();
+        self.group_set_map[&(components, Vec::new())]
+    }
+
     pub fn query_group<'a, G: IComponentGroup<'a>>(&'a self) -> G::Iterator {
-        let group_ty = TypeId::of::<G>();
-        let group_id = self.group_map[&group_ty];
+        let group_id = self.group_id::<G>();
         G::iter(&self.comp_map, self.group_storages.get(&group_id).unwrap())
     }
-}
 
-pub struct GroupIterator2<'a, A, B> {
-    chunks_a: &'a [ChunkPtr],
-    chunks_b: &'a [ChunkPtr],
-    chunk_data: &'a [ChunkData],
-    cur_chunk: usize,
-    cur_slot: usize,
-    _marker: PhantomData<(A, B)>,
-}
+    /// Iterate over all entities of the group `G` with mutable access.
+    ///
+    /// The query selects the components of the group, e.g. `(&Pos, &mut Vel)` for
+    /// the group `(Pos, Vel)`.
+    pub fn query_group_mut<'a, G: IComponentGroup<'a>, Q: Query<'a>>(&'a mut self) -> QueryIter<'a, Q, ()> {
+        let group_id = self.group_id::<G>();
+        QueryIter::new(self.query_context(), self.group_storages.get(&group_id).into_iter())
+    }
 
-impl<'a, A, B> Iterator for GroupIterator2<'a, A, B>
-where
-    A: Component,
-    B: Component,
-{
-    type Item = (&'a A, &'a B);
+    /// Iterate over all entities whose group contains the queried components.
+    pub fn query<'a, Q: Query<'a>>(&'a mut self) -> QueryIter<'a, Q, ()> {
+        self.query_filtered::<Q, ()>()
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        debug_assert!(self.cur_chunk < self.chunk_data.len());
-        if self.cur_slot >= self.chunk_data[self.cur_chunk].len {
-            self.cur_slot = 0;
-            self.cur_chunk += 1;
+    /// Iterate over all entities whose group contains the queried components
+    /// and passes the filter.
+    pub fn query_filtered<'a, Q: Query<'a>, F: Filter>(&'a mut self) -> QueryIter<'a, Q, F> {
+        self.query_iter()
+    }
+
+    /// Read-only variant of `query`, multiple queries can run on a shared world.
+    pub fn query_ref<'a, Q: ReadOnlyQuery<'a>>(&'a self) -> QueryIter<'a, Q, ()> {
+        self.query_iter()
+    }
+
+    /// Read-only variant of `query_filtered`.
+    pub fn query_ref_filtered<'a, Q: ReadOnlyQuery<'a>, F: Filter>(&'a self) -> QueryIter<'a, Q, F> {
+        self.query_iter()
+    }
+
+    /// Current tick, stamped onto chunks on insertion and mutable access.
+    pub fn tick(&self) -> Tick {
+        self.tick
+    }
+
+    /// Advance the world tick, e.g. before running a system.
+    ///
+    /// Returns the new tick, which the system passes as `since` on its next run
+    /// to only visit chunks changed by others in the meantime.
+    pub fn increment_tick(&mut self) -> Tick {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Variant of `query_filtered` evaluating `Changed` and `Added` filters
+    /// against the tick `since` instead of the world creation.
+    pub fn query_since<'a, Q: Query<'a>, F: Filter>(&'a mut self, since: Tick) -> QueryIter<'a, Q, F> {
+        QueryIter::new(self.query_context_since(since), self.sorted_groups())
+    }
+
+    /// Read-only variant of `query_since`.
+    pub fn query_ref_since<'a, Q: ReadOnlyQuery<'a>, F: Filter>(&'a self, since: Tick) -> QueryIter<'a, Q, F> {
+        QueryIter::new(self.query_context_since(since), self.sorted_groups())
+    }
+
+    /// Iterate over the entities of all chunks whose shared component `S` equals `value`.
+    pub fn query_shared<'a, Q, S>(&'a mut self, value: &S) -> QueryIter<'a, Q, With<S>>
+    where
+        Q: Query<'a>,
+        S: SharedComponent,
+    {
+        QueryIter::new(self.query_context_shared(value), self.sorted_groups())
+    }
+
+    /// Read-only variant of `query_shared`.
+    pub fn query_ref_shared<'a, Q, S>(&'a self, value: &S) -> QueryIter<'a, Q, With<S>>
+    where
+        Q: ReadOnlyQuery<'a>,
+        S: SharedComponent,
+    {
+        QueryIter::new(self.query_context_shared(value), self.sorted_groups())
+    }
+
+    /// Iterate over the chunks of all groups containing the queried components.
+    ///
+    /// Chunks are returned as slices, e.g. `(&[Entity], &[A], &mut [B])` for the query
+    /// `(Entity, &A, &mut B)`, which allows processing whole chunks in tight loops.
+    pub fn query_chunks<'a, Q: Query<'a>>(&'a mut self) -> ChunkIter<'a, Q, ()> {
+        self.query_chunks_filtered::<Q, ()>()
+    }
+
+    pub fn query_chunks_filtered<'a, Q: Query<'a>, F: Filter>(&'a mut self) -> ChunkIter<'a, Q, F> {
+        ChunkIter::new(self.query_context(), self.sorted_groups())
+    }
+
+    /// Chunk variant of `query_shared`, e.g. for batching all entities of a chunk
+    /// with the same material.
+    pub fn query_chunks_shared<'a, Q, S>(&'a mut self, value: &S) -> ChunkIter<'a, Q, With<S>>
+    where
+        Q: Query<'a>,
+        S: SharedComponent,
+    {
+        ChunkIter::new(self.query_context_shared(value), self.sorted_groups())
+    }
+
+    /// Read-only variant of `query_chunks_shared`.
+    pub fn query_chunks_ref_shared<'a, Q, S>(&'a self, value: &S) -> ChunkIter<'a, Q, With<S>>
+    where
+        Q: ReadOnlyQuery<'a>,
+        S: SharedComponent,
+    {
+        ChunkIter::new(self.query_context_shared(value), self.sorted_groups())
+    }
+
+    /// Chunk variant of `query_since`.
+    pub fn query_chunks_since<'a, Q: Query<'a>, F: Filter>(&'a mut self, since: Tick) -> ChunkIter<'a, Q, F> {
+        ChunkIter::new(self.query_context_since(since), self.sorted_groups())
+    }
+
+    /// `query_chunks_filtered` on a shared world.
+    ///
+    /// The caller has to ensure that mutably accessed components aren't accessed otherwise.
+    pub(crate) unsafe fn query_chunks_unchecked<'a, Q: Query<'a>, F: Filter>(&'a self) -> ChunkIter<'a, Q, F> {
+        ChunkIter::new(self.query_context(), self.sorted_groups())
+    }
+
+    /// Check if the entity hasn't been freed yet.
+    pub fn is_alive(&self, entity: Entity) -> bool {
+        self.entity_data(entity).is_ok()
+    }
+
+    pub fn get<C: Component>(&self, entity: Entity) -> Result<&C, LookupError> {
+        self.get_many::<&C>(entity)
+    }
+
+    pub fn get_mut<C: Component>(&mut self, entity: Entity) -> Result<&mut C, LookupError> {
+        self.get_many_mut::<&mut C>(entity)
+    }
+
+    /// Fetch a read-only query for a single entity, e.g. `(&Pos, Option<&Vel>)`.
+    pub fn get_many<'a, Q: ReadOnlyQuery<'a>>(&'a self, entity: Entity) -> Result<Q::Item, LookupError> {
+        unsafe { self.get_unchecked::<Q>(entity) }
+    }
+
+    /// Fetch a query for a single entity, e.g. `(&Pos, &mut Vel)`.
+    pub fn get_many_mut<'a, Q: Query<'a>>(&'a mut self, entity: Entity) -> Result<Q::Item, LookupError> {
+        unsafe { self.get_unchecked::<Q>(entity) }
+    }
+
+    /// Fetch a query for a single entity without borrowing the world mutably.
+    ///
+    /// The caller has to ensure that mutably accessed components aren't aliased.
+    unsafe fn get_unchecked<'a, Q: Query<'a>>(&'a self, entity: Entity) -> Result<Q::Item, LookupError> {
+        validate_access::<Q>();
+
+        let entity_data = self.entity_data(entity)?;
+        let ctx = self.query_context();
+        let group = &self.group_storages[&entity_data.group];
+        if !Q::matches(&ctx, group) {
+            return Err(LookupError::MissingComponent);
         }
 
-        if self.cur_chunk >= self.chunks_a.len() {
-            return None;
+        let state = Q::state(&ctx, group);
+        if !Q::matches_slot(&state, entity_data.chunk, entity_data.slot) {
+            return Err(LookupError::MissingComponent);
         }
+        Ok(Q::fetch(&ctx, &state, entity_data.chunk, entity_data.slot))
+    }
 
-        let chunk_a = unsafe {
-            ::std::slice::from_raw_parts(self.chunks_a[self.cur_chunk].ptr as *const A, CHUNK_SIZE)
-        };
-        let chunk_b = unsafe {
-            ::std::slice::from_raw_parts(self.chunks_b[self.cur_chunk].ptr as *const B, CHUNK_SIZE)
-        };
-        let item = (&chunk_a[self.cur_slot], &chunk_b[self.cur_slot]);
-        self.cur_slot += 1;
+    /// Define a component and make it accessible by reflection under its type name.
+    pub fn register_reflect<C: Component + Reflect>(&mut self) -> ComponentId {
+        assert_eq!(C::STORAGE, StorageHint::Dense, "Sparse components can't be reflected");
+        let id = self.define_component::<C>();
+        self.reflect.insert(
+            id,
+            ReflectEntry {
+                name: C::type_name(),
+                slot: reflect_slot::<C>,
+            },
+        );
+        id
+    }
 
-        Some(item)
+    /// All reflected components of an entity with their names, sorted by name.
+    pub fn reflect(&self, entity: Entity) -> Result<Vec<(&'static str, &Reflect)>, LookupError> {
+        let slots = self.reflect_slots(entity)?;
+        Ok(slots.into_iter().map(|(name, ptr)| (name, unsafe { &*ptr })).collect())
+    }
+
+    /// Mutable variant of `reflect`.
+    pub fn reflect_mut(&mut self, entity: Entity) -> Result<Vec<(&'static str, &mut Reflect)>, LookupError> {
+        // Each slot belongs to a different component storage.
+        let slots = self.reflect_slots(entity)?;
+        let entity_data = self.entity_data(entity)?;
+        let group = &self.group_storages[&entity_data.group];
+        for id in group.components.iter().filter(|id| self.reflect.contains_key(id)) {
+            group.comp_chunks[id][entity_data.chunk].mark_changed(self.tick);
+        }
+        Ok(slots.into_iter().map(|(name, ptr)| (name, unsafe { &mut *ptr })).collect())
+    }
+
+    pub fn reflect_component(&self, entity: Entity, name: &str) -> Result<&Reflect, LookupError> {
+        self.reflect(entity)?
+            .into_iter()
+            .find(|(component, _)| *component == name)
+            .map(|(_, component)| component)
+            .ok_or(LookupError::MissingComponent)
+    }
+
+    pub fn reflect_component_mut(&mut self, entity: Entity, name: &str) -> Result<&mut Reflect, LookupError> {
+        self.reflect_mut(entity)?
+            .into_iter()
+            .find(|(component, _)| *component == name)
+            .map(|(_, component)| component)
+            .ok_or(LookupError::MissingComponent)
+    }
+
+    fn reflect_slots(&self, entity: Entity) -> Result<Vec<(&'static str, *mut Reflect)>, LookupError> {
+        let entity_data = self.entity_data(entity)?;
+        let group = &self.group_storages[&entity_data.group];
+
+        let mut slots = group
+            .components
+            .iter()
+            .filter_map(|id| {
+                self.reflect.get(id).map(|entry| {
+                    let chunk = &group.comp_chunks[id][entity_data.chunk];
+                    (entry.name, unsafe { (entry.slot)(chunk, entity_data.slot) })
+                })
+            })
+            .collect::<Vec<_>>();
+        slots.sort_by_key(|(name, _)| *name);
+        Ok(slots)
+    }
+
+    fn entity_data(&self, entity: Entity) -> Result<EntityData, LookupError> {
+        match self.entities.get(entity.id as usize) {
+            Some(data) if data.generation == entity.generation && data.group != RESERVED_GROUP => Ok(*data),
+            _ => Err(LookupError::NoSuchEntity),
+        }
+    }
+
+    fn query_iter<'a, Q: Query<'a>, F: Filter>(&'a self) -> QueryIter<'a, Q, F> {
+        QueryIter::new(self.query_context(), self.sorted_groups())
+    }
+
+    fn query_context(&self) -> QueryContext {
+        self.query_context_since(0)
+    }
+
+    fn query_context_since(&self, since: Tick) -> QueryContext {
+        QueryContext {
+            comp_map: &self.comp_map,
+            sparse_sets: &self.sparse_sets,
+            tick: self.tick,
+            since,
+            shared: None,
+        }
+    }
+
+    fn query_context_shared<S: SharedComponent>(&self, value: &S) -> QueryContext {
+        let shared = self.comp_map.get(&TypeId::of::<S>()).map(|id| {
+            let index = self
+                .shared_values::<S>()
+                .iter()
+                .position(|v| v == value)
+                .unwrap_or(usize::max_value());
+            (*id, index)
+        });
+
+        QueryContext {
+            shared,
+            ..self.query_context()
+        }
+    }
+
+    fn sorted_groups(&self) -> impl Iterator<Item = &GroupStorage> {
+        let mut groups = self.group_storages.iter().collect::<Vec<_>>();
+        groups.sort_by_key(|(id, _)| **id);
+        groups.into_iter().map(|(_, group)| group)
     }
 }
 
-impl<'a, A, B> IComponentGroup<'a> for (A, B)
-where
-    A: Component,
-    B: Component,
-{
-    type BuildStream = (&'a [A], &'a [B]);
-    type Iterator = GroupIterator2<'a, A, B>;
+/// Split items sorted by their chunk into batches of the same chunk.
+fn chunk_batches<T>(items: Vec<((GroupId, ChunkId), T)>) -> Vec<Vec<T>> {
+    let mut batches: Vec<Vec<T>> = Vec::new();
+    let mut last = None;
+    for (chunk, item) in items {
+        if last != Some(chunk) {
+            batches.push(Vec::new());
+            last = Some(chunk);
+        }
+        batches.last_mut().unwrap().push(item);
+    }
+    batches
+}
 
-    fn iter(comp_map: &HashMap<TypeId, ComponentId>, group: &'a GroupStorage) -> Self::Iterator {
-        let ty_a = TypeId::of::<A>();
-        let ty_b = TypeId::of::<B>();
+impl Drop for World {
+    fn drop(&mut self) {
+        for group in self.group_storages.values() {
+            for (comp_id, chunks) in &group.comp_chunks {
+                let storage = &self.comp_storages[comp_id];
+                for (chunk, data) in chunks.iter().zip(&group.chunk_data) {
+                    storage.drop_slots(chunk, 0..data.len);
+                }
+            }
+        }
+    }
+}
 
-        let comp_id_a = comp_map[&ty_a];
-        let comp_id_b = comp_map[&ty_b];
+pub struct GroupIterator<'a, G> {
+    chunks: Vec<&'a [ChunkPtr]>,
+    chunk_data: &'a [ChunkData],
+    cur_chunk: usize,
+    cur_slot: usize,
+    _marker: PhantomData<G>,
+}
 
-        let chunks_a = group.comp_chunks.get(&comp_id_a).unwrap();
-        let chunks_b = group.comp_chunks.get(&comp_id_b).unwrap();
+impl<'a, G> GroupIterator<'a, G> {
+    /// Iterate over all slots of a group.
+    ///
+    /// `components` are the component types passed to `G::fetch` in order.
+    pub fn new(comp_map: &HashMap<TypeId, ComponentId>, group: &'a GroupStorage, components: &[TypeId]) -> Self {
+        let chunks = components
+            .iter()
+            .map(|ty| {
+                let chunks = group.comp_chunks.get(&comp_map[ty]);
+                &chunks.expect("Group queries can't access sparse components")[..]
+            })
+            .collect();
 
-        GroupIterator2 {
-            chunks_a: &chunks_a,
-            chunks_b: &chunks_b,
+        GroupIterator {
+            chunks,
             chunk_data: &group.chunk_data,
             cur_chunk: 0,
             cur_slot: 0,
             _marker: PhantomData,
         }
     }
+}
 
-    fn define_components(comp_map: &HashMap<TypeId, ComponentId>) -> Vec<ComponentId> {
-        let ty_a = TypeId::of::<A>();
-        let ty_b = TypeId::of::<B>();
+impl<'a, G> Iterator for GroupIterator<'a, G>
+where
+    G: IComponentGroup<'a>,
+{
+    type Item = G::Item;
 
-        vec![ENTITY_COMP_ID, comp_map[&ty_a], comp_map[&ty_b]]
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.cur_chunk < self.chunk_data.len()
+            && self.cur_slot >= self.chunk_data[self.cur_chunk].len
+        {
+            self.cur_slot = 0;
+            self.cur_chunk += 1;
+        }
+
+        if self.cur_chunk >= self.chunk_data.len() {
+            return None;
+        }
+
+        let item = unsafe { G::fetch(&self.chunks, self.cur_chunk, self.cur_slot) };
+        self.cur_slot += 1;
+
+        Some(item)
     }
+}
 
-    fn fill_slots(
-        comp_map: &HashMap<TypeId, ComponentId>,
-        comp_chunks: &mut HashMap<ComponentId, Vec<ChunkPtr>>,
-        chunk_id: ChunkId,
-        slot_base: SlotId,
-        stream: &Self::BuildStream,
-        stream_base: usize,
-        num: usize,
-    ) {
-        let ty_a = TypeId::of::<A>();
-        let ty_b = TypeId::of::<B>();
+macro_rules! impl_component_group {
+    ($($ty:ident: $idx:tt),*) => {
+        impl<'a, $($ty),*> IComponentGroup<'a> for ($($ty,)*)
+        where
+            $($ty: Component,)*
+        {
+            type BuildStream = ($(Vec<$ty>,)*);
+            type Iterator = GroupIterator<'a, Self>;
+            type Item = ($(&'a $ty,)*);
 
-        let comp_id_a = comp_map[&ty_a];
-        let comp_id_b = comp_map[&ty_b];
+            fn iter(comp_map: &HashMap<TypeId, ComponentId>, group: &'a GroupStorage) -> Self::Iterator {
+                GroupIterator::new(comp_map, group, &[$(TypeId::of::<$ty>()),*])
+            }
 
-        let start_slot = slot_base;
-        let end_slot = start_slot + num;
+            fn into_stream<Groups: IntoIterator<Item = Self>>(groups: Groups) -> Self::BuildStream {
+                let mut stream = ($(Vec::<$ty>::new(),)*);
+                for group in groups {
+                    $(stream.$idx.push(group.$idx);)*
+                }
+                stream
+            }
 
-        let start_entity = stream_base;
-        let end_entity = start_entity + num;
+            fn define_components(comp_map: &HashMap<TypeId, ComponentId>) -> Vec<ComponentId> {
+                vec![ENTITY_COMP_ID, $(comp_map[&TypeId::of::<$ty>()]),*]
+            }
 
-        {
-            let comp_a = comp_chunks.get_mut(&comp_id_a).unwrap(); // TODO: slow
-            let chunk = unsafe {
-                ::std::slice::from_raw_parts_mut(comp_a[chunk_id].ptr as *mut A, CHUNK_SIZE)
-            };
+            unsafe fn release_stream(stream: &mut Self::BuildStream, num: usize) {
+                $(
+                    assert_eq!(stream.$idx.len(), num);
+                    stream.$idx.set_len(0);
+                )*
+            }
 
-            chunk[start_slot..end_slot].clone_from_slice(&(stream.0)[start_entity..end_entity]);
-        }
+            unsafe fn fill_slots(
+                comp_map: &HashMap<TypeId, ComponentId>,
+                comp_chunks: &mut HashMap<ComponentId, Vec<ChunkPtr>>,
+                chunk_id: ChunkId,
+                slot_base: SlotId,
+                stream: &Self::BuildStream,
+                stream_base: usize,
+                num: usize,
+            ) {
+                let start_slot = slot_base;
+
+                let start_entity = stream_base;
+                let end_entity = start_entity + num;
+
+                $(
+                    // Sparse components aren't stored in the chunks.
+                    if $ty::STORAGE == StorageHint::Dense {
+                        let comp_id = comp_map[&TypeId::of::<$ty>()];
+                        let comp = comp_chunks.get_mut(&comp_id).unwrap(); // TODO: slow
+                        ptr::copy_nonoverlapping(
+                            stream.$idx.as_ptr().add(start_entity),
+                            (comp[chunk_id].ptr as *mut $ty).add(start_slot),
+                            end_entity - start_entity,
+                        );
+                    }
+                )*
+            }
 
-        {
-            let comp_b = comp_chunks.get_mut(&comp_id_b).unwrap(); // TODO: slow
-            let chunk = unsafe {
-                ::std::slice::from_raw_parts_mut(comp_b[chunk_id].ptr as *mut B, CHUNK_SIZE)
-            };
+            unsafe fn fill_sparse(sparse: &mut SparseSets, entities: &[Entity], stream: &Self::BuildStream, stream_base: usize) {
+                $(sparse.insert::<$ty>(entities, stream.$idx.as_ptr().add(stream_base));)*
+            }
 
-            chunk[start_slot..end_slot].clone_from_slice(&(stream.1)[start_entity..end_entity]);
+            unsafe fn fetch(chunks: &[&'a [ChunkPtr]], chunk: ChunkId, slot: SlotId) -> Self::Item {
+                ($(&*(chunks[$idx][chunk].ptr as *const $ty).add(slot),)*)
+            }
         }
-    }
+    };
 }
 
+impl_component_group!(A: 0);
+impl_component_group!(A: 0, B: 1);
+impl_component_group!(A: 0, B: 1, C: 2);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9, K: 10);
+impl_component_group!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9, K: 10, L: 11);
+
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    #[derive(Copy, Clone, Debug)]
+    use tanya_ecs_derive::Component;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tanya_jobs::frame::{FrameBuilder, WorldHandle};
+    use tanya_jobs::futures::executor::block_on;
+    use tanya_jobs::jobs::{JobSystem, ThreadPoolBuilder};
+
+    #[derive(Copy, Clone, Debug, Component)]
+    #[component(crate = "crate")]
     struct Foo {
         a: usize,
     }
-    impl Component for Foo {}
 
-    #[derive(Copy, Clone, Debug)]
+    #[derive(Copy, Clone, Debug, Component)]
+    #[component(crate = "crate")]
     struct Bar {}
-    impl Component for Bar {}
+
+    #[derive(Copy, Clone, Debug, PartialEq, Component)]
+    #[component(crate = "crate")]
+    struct Baz(u32);
+
+    #[derive(Copy, Clone, Debug, PartialEq, Component)]
+    #[component(crate = "crate")]
+    struct Qux(f32);
+
+    #[derive(Debug, Component)]
+    #[component(crate = "crate")]
+    struct Tracked(Arc<()>);
 
     #[test]
     fn allocate_entities_simple() {
@@ -530,9 +1748,8 @@ mod tests {
         let mut entities = [Entity::INVALID; 8];
         let mut entities2 = [Entity::INVALID; 8];
         let foo_data = (0..8).map(|a| Foo { a }).collect::<Vec<_>>();
-        let foo_data2 = [Foo { a: 10 }; 8];
-        let bar_data = [Bar {}; 8];
-        world.create_entities::<(Foo, Bar)>(&mut entities, (&foo_data, &bar_data));
+        let foo_data2 = vec![Foo { a: 10 }; 8];
+        world.create_entities::<(Foo, Bar)>(&mut entities, (foo_data, vec![Bar {}; 8]));
 
         println!("{:#?}", entities);
         println!("{:#?}", world.entities);
@@ -545,7 +1762,7 @@ mod tests {
 
         println!("{:#?}", world.entities);
 
-        world.create_entities::<(Foo, Bar)>(&mut entities2, (&foo_data2, &bar_data));
+        world.create_entities::<(Foo, Bar)>(&mut entities2, (foo_data2, vec![Bar {}; 8]));
 
         println!("{:#?}", entities);
         println!("{:#?}", entities2);
@@ -555,4 +1772,1150 @@ mod tests {
             println!("{:?}", (a, b));
         }
     }
+
+    #[test]
+    fn query_group_arity_4() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Bar>();
+        world.define_component::<Baz>();
+        world.define_component::<Qux>();
+
+        let num = 200;
+        let foo_data = (0..num).map(|a| Foo { a }).collect::<Vec<_>>();
+        let bar_data = vec![Bar {}; num];
+        let baz_data = (0..num).map(|i| Baz(i as u32 * 2)).collect::<Vec<_>>();
+        let qux_data = (0..num).map(|i| Qux(i as f32)).collect::<Vec<_>>();
+        let mut entities = vec![Entity::INVALID; num];
+        world.create_entities::<(Foo, Bar, Baz, Qux)>(
+            &mut entities,
+            (foo_data, bar_data, baz_data, qux_data),
+        );
+
+        let mut count = 0;
+        for (foo, _, baz, qux) in world.query_group::<(Foo, Bar, Baz, Qux)>() {
+            assert_eq!(baz.0, foo.a as u32 * 2);
+            assert_eq!(qux.0, foo.a as f32);
+            count += 1;
+        }
+        assert_eq!(count, num);
+    }
+
+    #[test]
+    fn query_across_groups() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Bar>();
+        world.define_component::<Baz>();
+
+        let mut entities_a = [Entity::INVALID; 4];
+        let foo_a = (0..4).map(|a| Foo { a }).collect::<Vec<_>>();
+        world.create_entities::<(Foo, Bar)>(&mut entities_a, (foo_a, vec![Bar {}; 4]));
+
+        let mut entities_b = [Entity::INVALID; 3];
+        let foo_b = (10..13).map(|a| Foo { a }).collect::<Vec<_>>();
+        let baz_b = vec![Baz(1), Baz(2), Baz(3)];
+        world.create_entities::<(Foo, Baz)>(&mut entities_b, (foo_b, baz_b));
+
+        let mut entities_c = [Entity::INVALID; 2];
+        world.create_entities::<(Bar, Baz)>(&mut entities_c, (vec![Bar {}; 2], vec![Baz(7); 2]));
+
+        assert_eq!(world.query::<&Foo>().count(), 7);
+        assert_eq!(world.query::<(&Foo, &Baz)>().count(), 3);
+        assert_eq!(world.query_filtered::<&Foo, Not<Baz>>().count(), 4);
+        assert_eq!(world.query_filtered::<&Baz, With<Bar>>().count(), 2);
+
+        for (foo, baz) in world.query::<(&Foo, &mut Baz)>() {
+            baz.0 += foo.a as u32;
+        }
+        let mut baz = world
+            .query_filtered::<(Entity, &Baz), With<Foo>>()
+            .map(|(entity, baz)| (entity.id, baz.0))
+            .collect::<Vec<_>>();
+        baz.sort_by_key(|(id, _)| *id);
+        let expected = entities_b
+            .iter()
+            .zip(&[11, 13, 15])
+            .map(|(entity, baz)| (entity.id, *baz))
+            .collect::<Vec<_>>();
+        assert_eq!(baz, expected);
+
+        let num_optional = world
+            .query::<(&Foo, Option<&Baz>)>()
+            .filter(|(_, baz)| baz.is_some())
+            .count();
+        assert_eq!(num_optional, 3);
+    }
+
+    #[test]
+    fn query_group_mut() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Baz>();
+        world.define_component::<Qux>();
+
+        let foo_data = (0..300).map(|a| Foo { a }).collect::<Vec<_>>();
+        let baz_data = vec![Baz(0); 300];
+        world.create_entities::<(Foo, Baz)>(&mut vec![Entity::INVALID; 300], (foo_data.clone(), baz_data.clone()));
+        world.create_entities::<(Foo, Baz, Qux)>(
+            &mut [Entity::INVALID; 2],
+            (foo_data[..2].to_vec(), baz_data[..2].to_vec(), vec![Qux(0.0); 2]),
+        );
+
+        for (foo, baz) in world.query_group_mut::<(Foo, Baz), (&Foo, &mut Baz)>() {
+            baz.0 = foo.a as u32 + 1;
+        }
+
+        let updated = world.query_ref::<&Baz>().filter(|baz| baz.0 > 0).count();
+        assert_eq!(updated, 300);
+        for (foo, baz) in world.query_group::<(Foo, Baz)>() {
+            assert_eq!(baz.0, foo.a as u32 + 1);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn query_aliasing() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Baz>();
+        world.create_entities::<(Foo, Baz)>(&mut [Entity::INVALID; 1], (vec![Foo { a: 0 }], vec![Baz(0)]));
+
+        world.query::<(&Foo, &mut Foo)>().count();
+    }
+
+    // Number of `(Foo, Baz)` entities per chunk of `foo_baz_world`.
+    const FOO_BAZ_CHUNK: usize = 128;
+
+    fn foo_baz_world() -> World {
+        let entity_bytes = mem::size_of::<Entity>() + mem::size_of::<Foo>() + mem::size_of::<Baz>();
+        World::with_chunk_bytes(FOO_BAZ_CHUNK * entity_bytes)
+    }
+
+    #[test]
+    fn chunk_capacity() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Bar>();
+        world.define_component::<Baz>();
+
+        let foo_baz = world.define_group::<(Foo, Baz)>();
+        let bar = world.define_group::<(Bar,)>();
+        let foo_baz_bytes = mem::size_of::<Entity>() + mem::size_of::<Foo>() + mem::size_of::<Baz>();
+        assert_eq!(world.group_storages[&foo_baz].chunk_capacity(), DEFAULT_CHUNK_BYTES / foo_baz_bytes);
+        // Tags don't take up any space in chunks.
+        let bar_bytes = mem::size_of::<Entity>();
+        assert_eq!(world.group_storages[&bar].chunk_capacity(), DEFAULT_CHUNK_BYTES / bar_bytes);
+
+        let mut world = World::with_chunk_bytes(1);
+        world.define_component::<Foo>();
+        let foo = world.define_group::<(Foo,)>();
+        assert_eq!(world.group_storages[&foo].chunk_capacity(), 1);
+        world.spawn_batch((0..3).map(|a| (Foo { a },)));
+        assert_eq!(world.query_chunks::<&Foo>().count(), 3);
+    }
+
+    #[test]
+    fn query_chunks() {
+        let mut world = foo_baz_world();
+        world.define_component::<Foo>();
+        world.define_component::<Baz>();
+
+        let num = 300;
+        let foo_data = (0..num).map(|a| Foo { a }).collect::<Vec<_>>();
+        let baz_data = vec![Baz(0); num];
+        let mut entities = vec![Entity::INVALID; num];
+        world.create_entities::<(Foo, Baz)>(&mut entities, (foo_data, baz_data));
+
+        let mut lens = Vec::new();
+        for (handles, foo, baz) in world.query_chunks::<(Entity, &Foo, &mut Baz)>() {
+            assert_eq!(handles.len(), foo.len());
+            assert_eq!(foo.len(), baz.len());
+            for (foo, baz) in foo.iter().zip(baz.iter_mut()) {
+                baz.0 = foo.a as u32;
+            }
+            lens.push(foo.len());
+        }
+        assert_eq!(lens, vec![FOO_BAZ_CHUNK, FOO_BAZ_CHUNK, num - 2 * FOO_BAZ_CHUNK]);
+
+        for (entity, baz) in world.query::<(Entity, &Baz)>() {
+            let index = entities.iter().position(|e| e.id == entity.id).unwrap();
+            assert_eq!(baz.0, index as u32);
+        }
+    }
+
+    #[test]
+    fn add_remove_component() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Bar>();
+
+        let mut entities = [Entity::INVALID; 3];
+        let foo_data = (0..3).map(|a| Foo { a }).collect::<Vec<_>>();
+        world.create_entities::<(Foo, Bar)>(&mut entities, (foo_data, vec![Bar {}; 3]));
+
+        world.add_component(entities[0], Baz(5));
+        assert_eq!(world.query::<(&Foo, &Bar)>().count(), 3);
+        assert_eq!(world.query_group::<(Foo, Bar)>().count(), 2);
+        let with_baz = world
+            .query::<(Entity, &Foo, &Baz)>()
+            .map(|(e, foo, baz)| (e.id, foo.a, baz.0))
+            .collect::<Vec<_>>();
+        assert_eq!(with_baz, vec![(entities[0].id, 0, 5)]);
+
+        assert_eq!(world.remove_component::<Bar>(entities[0]).is_some(), true);
+        assert_eq!(world.remove_component::<Bar>(entities[0]).is_none(), true);
+        assert_eq!(world.query::<&Bar>().count(), 2);
+        assert_eq!(world.query_group::<(Foo, Baz)>().count(), 1);
+
+        world.add_components(&entities[1..], vec![Baz(1), Baz(2)]);
+        world.remove_components::<Bar>(&entities[1..]);
+        let mut foo_baz = world
+            .query_group::<(Foo, Baz)>()
+            .map(|(foo, baz)| (foo.a, baz.0))
+            .collect::<Vec<_>>();
+        foo_baz.sort();
+        assert_eq!(foo_baz, vec![(0, 5), (1, 1), (2, 2)]);
+        assert_eq!(world.query::<&Bar>().count(), 0);
+
+        world.free_entities(&entities);
+        assert_eq!(world.query::<&Foo>().count(), 0);
+    }
+
+    #[test]
+    fn batch_add_remove_components() {
+        // Small chunks to spread the entities over many chunks of each group.
+        let mut world = World::with_chunk_bytes(64);
+        world.define_component::<Foo>();
+        world.define_component::<Baz>();
+        world.define_component::<Tracked>();
+
+        let counter = Arc::new(());
+        let mut entities = [Entity::INVALID; 64];
+        let foo_data = (0..64).map(|a| Foo { a }).collect::<Vec<_>>();
+        world.create_entities::<(Foo,)>(&mut entities, (foo_data,));
+
+        // Scattered order across chunks, the last value of duplicates wins.
+        let mut targets = (0..64).rev().step_by(3).map(|i| entities[i]).collect::<Vec<_>>();
+        targets.push(entities[63]);
+        let values = targets.iter().enumerate().map(|(i, e)| Baz(e.id * 100 + i as u32)).collect::<Vec<_>>();
+        world.add_components(&targets, values);
+        world.add_components(&targets, targets.iter().map(|_| Tracked(counter.clone())));
+        assert_eq!(Arc::strong_count(&counter), 1 + 22);
+
+        for (i, entity) in entities.iter().enumerate() {
+            assert_eq!(world.get::<Foo>(*entity).unwrap().a, i);
+            match world.get::<Baz>(*entity) {
+                Ok(baz) if i == 63 => assert_eq!(*baz, Baz(63 * 100 + 22)),
+                Ok(baz) => assert_eq!(baz.0 / 100, i as u32),
+                Err(_) => assert!(i % 3 != 0),
+            }
+        }
+
+        world.remove_components::<Tracked>(&targets);
+        assert_eq!(Arc::strong_count(&counter), 1);
+        world.remove_components::<Baz>(&entities);
+        assert_eq!(world.query::<&Baz>().count(), 0);
+        assert_eq!(world.query_group::<(Foo,)>().count(), 64);
+        for (i, entity) in entities.iter().enumerate() {
+            assert_eq!(world.get::<Foo>(*entity).unwrap().a, i);
+        }
+    }
+
+    #[test]
+    fn get_by_entity() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Bar>();
+
+        let mut entities = [Entity::INVALID; 2];
+        world.create_entities::<(Foo,)>(&mut entities, (vec![Foo { a: 1 }, Foo { a: 2 }],));
+
+        assert!(world.is_alive(entities[0]));
+        assert_eq!(world.get::<Foo>(entities[1]).unwrap().a, 2);
+        assert_eq!(world.get::<Bar>(entities[1]).err(), Some(LookupError::MissingComponent));
+
+        world.get_mut::<Foo>(entities[0]).unwrap().a = 3;
+        {
+            let (entity, foo, bar) = world.get_many::<(Entity, &Foo, Option<&Bar>)>(entities[0]).unwrap();
+            assert_eq!(entity.id, entities[0].id);
+            assert_eq!(foo.a, 3);
+            assert!(bar.is_none());
+        }
+
+        world.free_entities(&entities[..1]);
+        assert!(!world.is_alive(entities[0]));
+        assert_eq!(world.get::<Foo>(entities[0]).err(), Some(LookupError::NoSuchEntity));
+        assert_eq!(world.get::<Foo>(entities[1]).unwrap().a, 2);
+    }
+
+    #[test]
+    fn free_entities_twice() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        let entities = world.spawn_batch((0..2).map(|a| (Foo { a },)));
+
+        world.free_entities(&entities[..1]);
+        world.free_entities(&[entities[0], entities[0]]);
+        assert!(!world.is_alive(entities[0]));
+        assert_eq!(world.get::<Foo>(entities[1]).unwrap().a, 1);
+
+        // The id is only handed out once.
+        let reused = world.spawn_batch((2..4).map(|a| (Foo { a },)));
+        assert_ne!(reused[0].id, reused[1].id);
+        assert_eq!(reused.iter().filter(|e| e.id == entities[0].id).count(), 1);
+        world.free_entities(&entities[..1]);
+        assert_eq!(world.query_ref::<&Foo>().map(|foo| foo.a).collect::<Vec<_>>(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn drop_components() {
+        let counter = Arc::new(());
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Tracked>();
+
+        let mut entities = [Entity::INVALID; 200];
+        let tracked = (0..200).map(|_| Tracked(counter.clone())).collect::<Vec<_>>();
+        let foo_data = (0..200).map(|a| Foo { a }).collect::<Vec<_>>();
+        world.create_entities::<(Foo, Tracked)>(&mut entities, (foo_data, tracked));
+        assert_eq!(Arc::strong_count(&counter), 201);
+
+        world.free_entities(&entities[..10]);
+        assert_eq!(Arc::strong_count(&counter), 191);
+
+        let removed = world.remove_component::<Tracked>(entities[10]).unwrap();
+        assert_eq!(Arc::strong_count(&counter), 191);
+        drop(removed);
+        assert_eq!(Arc::strong_count(&counter), 190);
+
+        world.add_component(entities[11], Tracked(counter.clone()));
+        assert_eq!(Arc::strong_count(&counter), 190);
+
+        drop(world);
+        assert_eq!(Arc::strong_count(&counter), 1);
+    }
+
+    #[test]
+    fn compact_chunks() {
+        let mut world = foo_baz_world();
+        world.define_component::<Foo>();
+        world.define_component::<Baz>();
+
+        let num = 300;
+        let mut entities = vec![Entity::INVALID; num];
+        let foo_data = (0..num).map(|a| Foo { a }).collect::<Vec<_>>();
+        let baz_data = (0..num).map(|i| Baz(i as u32)).collect::<Vec<_>>();
+        world.create_entities::<(Foo, Baz)>(&mut entities, (foo_data, baz_data));
+
+        let freed = entities[..256].iter().cloned().step_by(2).collect::<Vec<_>>();
+        world.free_entities(&freed);
+        assert_eq!(world.query_chunks::<&Foo>().count(), 3);
+
+        assert!(!world.compact_incremental(10));
+        world.compact();
+        assert!(world.compact_incremental(10));
+        assert_eq!(world.query_chunks::<&Foo>().count(), 2);
+        assert_eq!(world.query::<&Foo>().count(), num - freed.len());
+
+        for (i, entity) in entities.iter().enumerate() {
+            if i < 256 && i % 2 == 0 {
+                assert!(!world.is_alive(*entity));
+            } else {
+                assert_eq!(world.get::<Foo>(*entity).unwrap().a, i);
+                assert_eq!(world.get::<Baz>(*entity).unwrap().0, i as u32);
+            }
+        }
+
+        // Released chunks are reused by new entities.
+        world.free_entities(&entities[256..]);
+        let foo_data = (0..200).map(|a| Foo { a }).collect::<Vec<_>>();
+        world.create_entities::<(Foo, Baz)>(&mut [Entity::INVALID; 200], (foo_data, vec![Baz(0); 200]));
+        assert_eq!(world.query_chunks::<&Foo>().count(), 3);
+    }
+
+    #[test]
+    fn apply_commands() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Bar>();
+        world.define_component::<Baz>();
+
+        let mut entities = [Entity::INVALID; 2];
+        world.create_entities::<(Foo,)>(&mut entities, (vec![Foo { a: 0 }, Foo { a: 1 }],));
+
+        let mut commands = world.commands();
+        let recorded = ::std::thread::spawn(move || {
+            let mut spawned = [Entity::INVALID; 3];
+            let foo_data = (10..13).map(|a| Foo { a }).collect::<Vec<_>>();
+            commands.create_entities::<(Foo, Bar)>(&mut spawned, (foo_data, vec![Bar {}; 3]));
+            commands.add_component(spawned[0], Baz(4));
+            commands.remove_component::<Bar>(spawned[1]);
+            commands.free_entities(&entities[..1]);
+            (commands, spawned)
+        });
+        let (commands, spawned) = recorded.join().unwrap();
+
+        // Entities created directly don't collide with reserved ids.
+        let mut direct = [Entity::INVALID; 1];
+        world.create_entities::<(Foo,)>(&mut direct, (vec![Foo { a: 20 }],));
+        assert!(spawned.iter().all(|e| e.id != direct[0].id));
+
+        assert_eq!(commands.len(), 4);
+        world.apply(commands);
+
+        assert!(!world.is_alive(entities[0]));
+        assert_eq!(world.get::<Foo>(entities[1]).unwrap().a, 1);
+        assert_eq!(world.get::<Foo>(direct[0]).unwrap().a, 20);
+        assert_eq!(world.get::<Baz>(spawned[0]).unwrap().0, 4);
+        assert!(world.get::<Bar>(spawned[1]).is_err());
+        assert!(world.get::<Bar>(spawned[2]).is_ok());
+        let mut foo = world.query::<&Foo>().map(|foo| foo.a).collect::<Vec<_>>();
+        foo.sort();
+        assert_eq!(foo, vec![1, 10, 11, 12, 20]);
+    }
+
+    #[test]
+    fn commands_skip_dead_entities() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Baz>();
+
+        let mut entities = [Entity::INVALID; 2];
+        world.create_entities::<(Foo,)>(&mut entities, (vec![Foo { a: 0 }, Foo { a: 1 }],));
+
+        let mut first = world.commands();
+        let mut second = world.commands();
+        first.free_entities(&entities[..1]);
+        second.free_entities(&[entities[0], entities[1], entities[1]]);
+        second.add_component(entities[0], Baz(1));
+        second.remove_component::<Foo>(entities[1]);
+
+        world.apply(first);
+        world.apply(second);
+        assert!(!world.is_alive(entities[0]));
+        assert!(!world.is_alive(entities[1]));
+        assert_eq!(world.query::<&Foo>().count(), 0);
+        assert_eq!(world.query::<&Baz>().count(), 0);
+
+        // Freed ids are reused with a new generation.
+        let mut reused = [Entity::INVALID; 1];
+        world.create_entities::<(Foo,)>(&mut reused, (vec![Foo { a: 2 }],));
+        let mut commands = world.commands();
+        commands.add_component(Entity { id: reused[0].id, generation: 0 }, Baz(2));
+        world.apply(commands);
+        assert!(world.get::<Baz>(reused[0]).is_err());
+    }
+
+    #[test]
+    fn drop_commands_without_apply() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+
+        let mut dropped = world.commands();
+        let mut pending = world.commands();
+        let mut reserved = [Entity::INVALID; 2];
+        dropped.create_entities::<(Foo,)>(&mut reserved, (vec![Foo { a: 0 }, Foo { a: 1 }],));
+        let mut spawned = [Entity::INVALID; 1];
+        pending.create_entities::<(Foo,)>(&mut spawned, (vec![Foo { a: 2 }],));
+        drop(dropped);
+
+        // Reserved entities aren't alive before their buffer is applied.
+        world.apply(world.commands());
+        assert!(!world.is_alive(reserved[0]));
+        assert!(!world.is_alive(reserved[1]));
+        assert!(!world.is_alive(spawned[0]));
+        assert_eq!(world.query::<Entity>().count(), 0);
+
+        world.apply(pending);
+        assert_eq!(world.query::<Entity>().collect::<Vec<_>>(), spawned);
+        assert_eq!(world.get::<Foo>(spawned[0]).unwrap().a, 2);
+
+        // Ids of the dropped buffer are reused with a new generation.
+        let mut reused = [Entity::INVALID; 2];
+        world.create_entities::<(Foo,)>(&mut reused, (vec![Foo { a: 3 }, Foo { a: 4 }],));
+        for (entity, reserved) in reused.iter().zip(&reserved) {
+            assert_eq!(entity.id, reserved.id);
+            assert_ne!(entity.generation, reserved.generation);
+            assert!(!world.is_alive(*reserved));
+        }
+        assert_eq!(world.query::<Entity>().count(), 3);
+    }
+
+    #[test]
+    fn derived_component_group() {
+        use tanya_ecs_derive::ComponentGroup;
+
+        #[derive(Debug, PartialEq, Component)]
+        #[component(crate = "crate")]
+        struct Vel(f32);
+
+        #[derive(Debug, PartialEq, Component)]
+        #[component(crate = "crate")]
+        struct Hull(String);
+
+        #[derive(ComponentGroup)]
+        #[group(crate = "crate")]
+        struct Ship {
+            pos: Qux,
+            vel: Vel,
+            hull: Hull,
+        }
+
+        let mut world = World::new();
+        world.define_component::<Qux>();
+        world.define_component::<Vel>();
+        world.define_component::<Hull>();
+
+        let ships = (0..200).map(|i| Ship {
+            pos: Qux(i as f32),
+            vel: Vel(-(i as f32)),
+            hull: Hull(format!("hull{}", i)),
+        });
+        let entities = world.spawn_batch(ships);
+        assert_eq!(entities.len(), 200);
+
+        let ships = world
+            .query_group::<Ship>()
+            .map(|ship| (ship.pos.0, ship.vel.0, ship.hull.0.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(ships.len(), 200);
+        for (i, ship) in ships.iter().enumerate() {
+            assert_eq!(*ship, (i as f32, -(i as f32), format!("hull{}", i)));
+        }
+        assert_eq!(world.get::<Hull>(entities[150]).unwrap().0, "hull150");
+
+        // The derived group shares the group of the equivalent tuple.
+        world.spawn_batch(vec![(Qux(1.0), Vel(2.0), Hull("tuple".into()))]);
+        assert_eq!(world.query_group::<Ship>().count(), 201);
+
+        world.free_entities(&entities);
+        assert_eq!(world.query::<&Hull>().count(), 1);
+    }
+
+    #[test]
+    fn spawn_batch() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Baz>();
+
+        let entities = world.spawn_batch((0..3).map(|i| (Foo { a: i }, Baz(i as u32 * 2))));
+        assert_eq!(entities.len(), 3);
+        for (i, entity) in entities.iter().enumerate() {
+            let (foo, baz) = world.get_many::<(&Foo, &Baz)>(*entity).unwrap();
+            assert_eq!((foo.a, baz.0), (i, i as u32 * 2));
+        }
+    }
+
+    #[test]
+    fn change_ticks() {
+        let mut world = foo_baz_world();
+        world.define_component::<Foo>();
+        world.define_component::<Baz>();
+        world.define_component::<Qux>();
+
+        let foo_data = (0..200).map(|a| Foo { a }).collect::<Vec<_>>();
+        let mut entities = [Entity::INVALID; 200];
+        world.create_entities::<(Foo, Baz)>(&mut entities, (foo_data, vec![Baz(0); 200]));
+        assert_eq!(world.query_filtered::<&Foo, Added<Foo>>().count(), 200);
+
+        let since = world.increment_tick();
+        assert_eq!(world.query_since::<&Foo, Changed<Foo>>(since).count(), 0);
+
+        world.increment_tick();
+        world.get_mut::<Foo>(entities[0]).unwrap().a = 1000;
+        world.add_component(entities[150], Qux(1.0));
+
+        assert_eq!(world.query_since::<&Foo, Changed<Foo>>(since).count(), FOO_BAZ_CHUNK);
+        assert_eq!(world.query_chunks_since::<&Foo, Changed<Foo>>(since).count(), 1);
+        assert_eq!(world.query_ref_since::<&Baz, Changed<Baz>>(since).count(), 0);
+        assert_eq!(world.query_ref_since::<&Foo, Added<Foo>>(since).count(), 0);
+        assert_eq!(world.query_ref_since::<Entity, Added<Qux>>(since).count(), 1);
+        assert_eq!(world.query_ref_since::<Entity, (Changed<Foo>, Not<Qux>)>(since).count(), FOO_BAZ_CHUNK);
+
+        let since = world.increment_tick();
+        for foo in world.query_since::<&mut Foo, Changed<Foo>>(since) {
+            foo.a += 1;
+        }
+        world.increment_tick();
+        assert_eq!(world.query_ref_since::<&Foo, Changed<Foo>>(since).count(), 0);
+    }
+
+    #[derive(Debug, Component)]
+    #[component(crate = "crate")]
+    struct Offset(f32);
+    #[derive(Debug, Component)]
+    #[component(crate = "crate")]
+    struct Position(f32);
+
+    impl LocalTransform for Offset {
+        type World = Position;
+
+        fn to_world(&self, parent: Option<&Position>) -> Position {
+            Position(parent.map(|p| p.0).unwrap_or(0.0) + self.0)
+        }
+    }
+
+    #[test]
+    fn hierarchy() {
+        let mut world = World::new();
+        world.define_component::<Offset>();
+        world.define_component::<Position>();
+
+        let entities = world.spawn_batch((0..5).map(|i| (Offset(i as f32), Position(0.0))));
+        let [root, a, b, c, d] = [entities[0], entities[1], entities[2], entities[3], entities[4]];
+        world.set_parent(a, root);
+        world.set_parent(b, root);
+        world.set_parent(c, a);
+        world.set_parent(d, c);
+
+        let order = world.bfs(root).collect::<Vec<_>>();
+        assert_eq!(order, vec![(root, 0), (a, 1), (b, 1), (c, 2), (d, 3)]);
+
+        world.propagate_transforms::<Offset>();
+        let positions = entities
+            .iter()
+            .map(|e| world.get::<Position>(*e).unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(positions, vec![0.0, 1.0, 2.0, 4.0, 8.0]);
+
+        // Reparenting detaches from the previous parent.
+        world.set_parent(d, b);
+        assert_eq!(world.children(c), &[]);
+        assert_eq!(world.children(b), &[d]);
+
+        world.free_entities(&[a]);
+        assert_eq!(world.children(root), &[b]);
+        assert_eq!(world.parent(c), None);
+        world.propagate_transforms::<Offset>();
+        assert_eq!(world.get::<Position>(c).unwrap().0, 3.0);
+        assert_eq!(world.get::<Position>(d).unwrap().0, 6.0);
+    }
+
+    #[test]
+    fn hierarchy_untransformed_ancestors() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Offset>();
+        world.define_component::<Position>();
+
+        // Roots and inner nodes without a local transform.
+        let groups = world.spawn_batch((0..2).map(|a| (Foo { a },)));
+        let entities = world.spawn_batch((1..4).map(|i| (Offset(i as f32), Position(0.0))));
+        world.set_parent(entities[0], groups[0]);
+        world.set_parent(groups[1], entities[0]);
+        world.set_parent(entities[1], groups[1]);
+        world.set_parent(entities[2], entities[1]);
+
+        world.propagate_transforms::<Offset>();
+        let positions = entities
+            .iter()
+            .map(|e| world.get::<Position>(*e).unwrap().0)
+            .collect::<Vec<_>>();
+        // The transform chain restarts below entities without a world transform.
+        assert_eq!(positions, vec![1.0, 2.0, 5.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "is a descendant")]
+    fn hierarchy_cycle() {
+        let mut world = World::new();
+        world.define_component::<Offset>();
+        let entities = world.spawn_batch((0..2).map(|i| (Offset(i as f32),)));
+        world.set_parent(entities[1], entities[0]);
+        world.set_parent(entities[0], entities[1]);
+    }
+
+    // Run a frame on a job world containing `world`.
+    fn run_frame<F>(world: World, build: F)
+    where
+        F: FnOnce(&mut FrameBuilder, &WorldHandle) + Send,
+    {
+        let mut jobs = JobSystem::new(ThreadPoolBuilder::new().num_threads(4).build().unwrap());
+        let mut job_world = tanya_jobs::world::World::new();
+        job_world.add_resource(world);
+        jobs.scope(|scope| {
+            let mut frame = FrameBuilder::new(&scope);
+            let ecs = frame.access(&mut job_world);
+            build(&mut frame, &ecs);
+            block_on(frame.dispatch());
+        });
+    }
+
+    // Wait until two tasks arrived, returns false on timeout.
+    fn rendezvous(arrived: &AtomicUsize) -> bool {
+        arrived.fetch_add(1, Ordering::SeqCst);
+        let start = Instant::now();
+        while arrived.load(Ordering::SeqCst) < 2 {
+            if start.elapsed() > Duration::from_secs(5) {
+                return false;
+            }
+            thread::yield_now();
+        }
+        true
+    }
+
+    #[test]
+    fn par_for_each_chunk() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Bar>();
+        world.define_component::<Baz>();
+        // Enough entities for multiple chunks and thereby tasks.
+        world.spawn_batch((0..5000).map(|a| (Foo { a }, Baz(2))));
+        world.spawn_batch((0..100).map(|a| (Foo { a }, Bar {}, Baz(3))));
+
+        let (tx, rx) = mpsc::channel();
+        run_frame(world, move |frame, ecs| {
+            let _ = jobs::par_for_each_chunk::<(&Baz, &mut Foo), _>(frame, ecs, |(baz, foo)| {
+                for (foo, baz) in foo.iter_mut().zip(baz) {
+                    foo.a *= baz.0 as usize;
+                }
+            });
+            let _ = jobs::par_for_each_chunk_filtered::<&mut Foo, With<Bar>, _>(frame, ecs, |foo| {
+                for foo in foo {
+                    foo.a += 1;
+                }
+            });
+
+            let world = jobs::access::<&Foo>(frame, ecs);
+            let _ = frame.spawn_job(async move {
+                let values = world.query_ref::<&Foo>().map(|foo| foo.a).collect::<Vec<_>>();
+                tx.send(values).unwrap();
+            });
+        });
+
+        let expected = (0..5000).map(|a| a * 2).chain((0..100).map(|a| a * 3 + 1));
+        assert_eq!(rx.recv().unwrap(), expected.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn par_disjoint_queries() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Baz>();
+        world.spawn_batch((0..4).map(|a| (Foo { a }, Baz(a as u32))));
+
+        // The chunk tasks of both jobs wait for each other, which only succeeds
+        // if the jobs run concurrently.
+        let arrived = Arc::new(AtomicUsize::new(0));
+        let concurrent = Arc::new(AtomicUsize::new(0));
+        let (arrived_foo, concurrent_foo) = (arrived.clone(), concurrent.clone());
+        let (arrived_baz, concurrent_baz) = (arrived.clone(), concurrent.clone());
+        run_frame(world, move |frame, ecs| {
+            let _ = jobs::par_for_each_chunk::<&mut Foo, _>(frame, ecs, move |_| {
+                if rendezvous(&arrived_foo) {
+                    concurrent_foo.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+            let _ = jobs::par_for_each_chunk::<&mut Baz, _>(frame, ecs, move |_| {
+                if rendezvous(&arrived_baz) {
+                    concurrent_baz.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+        });
+
+        assert_eq!(concurrent.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn par_propagate_transforms() {
+        let mut world = World::new();
+        world.define_component::<Offset>();
+        world.define_component::<Position>();
+
+        // Levels wider than a transform batch.
+        let roots = world.spawn_batch((0..2).map(|i| (Offset(i as f32), Position(0.0))));
+        let children = world.spawn_batch((0..600).map(|_| (Offset(1.0), Position(0.0))));
+        let grandchildren = world.spawn_batch((0..600).map(|_| (Offset(2.0), Position(0.0))));
+        for (i, (child, grandchild)) in children.iter().zip(&grandchildren).enumerate() {
+            world.set_parent(*child, roots[i % 2]);
+            world.set_parent(*grandchild, *child);
+        }
+
+        let (tx, rx) = mpsc::channel();
+        run_frame(world, move |frame, ecs| {
+            let _ = jobs::propagate_transforms::<Offset>(frame, ecs);
+
+            let world = jobs::access::<&Position>(frame, ecs);
+            let _ = frame.spawn_job(async move {
+                let positions = grandchildren
+                    .iter()
+                    .map(|e| world.get::<Position>(*e).unwrap().0)
+                    .collect::<Vec<_>>();
+                tx.send(positions).unwrap();
+            });
+        });
+
+        let expected = (0..600).map(|i| (i % 2) as f32 + 3.0).collect::<Vec<_>>();
+        assert_eq!(rx.recv().unwrap(), expected);
+    }
+
+    #[derive(Clone, Debug, PartialEq, Component)]
+    #[component(crate = "crate")]
+    struct Material(u32);
+    impl SharedComponent for Material {}
+
+    #[test]
+    fn shared_components() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Baz>();
+
+        let entities = world.spawn_batch((0..10).map(|a| (Foo { a },)));
+        for (i, entity) in entities.iter().enumerate() {
+            world.add_shared_component(*entity, Material(i as u32 % 2));
+        }
+        assert_eq!(world.shared_values::<Material>(), &[Material(0), Material(1)]);
+        assert_eq!(world.get_shared::<Material>(entities[3]), Ok(&Material(1)));
+
+        {
+            let chunks = world
+                .query_chunks_ref_shared::<&Foo, Material>(&Material(0))
+                .collect::<Vec<_>>();
+            assert_eq!(chunks.len(), 1);
+            assert_eq!(chunks[0].len(), 5);
+            assert!(chunks[0].iter().all(|foo| foo.a % 2 == 0));
+        }
+        assert_eq!(world.query_ref_shared::<&Foo, Material>(&Material(1)).count(), 5);
+        assert_eq!(world.query_ref_shared::<&Foo, Material>(&Material(2)).count(), 0);
+
+        world.add_shared_component(entities[0], Material(1));
+        assert_eq!(world.query_ref_shared::<&Foo, Material>(&Material(1)).count(), 6);
+
+        assert_eq!(world.remove_shared_component::<Material>(entities[1]), Some(Material(1)));
+        assert_eq!(world.get_shared::<Material>(entities[1]), Err(LookupError::MissingComponent));
+        assert_eq!(world.get::<Foo>(entities[1]).unwrap().a, 1);
+        assert_eq!(world.query_ref_filtered::<&Foo, Not<Material>>().count(), 1);
+
+        world.add_component(entities[2], Baz(2));
+        assert_eq!(world.get_shared::<Material>(entities[2]), Ok(&Material(0)));
+
+        world.compact();
+        for (i, entity) in entities.iter().enumerate() {
+            assert_eq!(world.get::<Foo>(*entity).unwrap().a, i);
+        }
+        assert_eq!(world.query_ref_shared::<&Foo, Material>(&Material(0)).count(), 4);
+    }
+
+    #[derive(Debug, Default, Component)]
+    #[component(crate = "crate", name = "tag", storage = "sparse", default)]
+    struct Tag;
+
+    #[test]
+    fn component_metadata() {
+        assert_eq!(Foo::STORAGE, StorageHint::Dense);
+        assert!(Foo::type_name().ends_with("Foo"));
+        assert!(Foo::default_value().is_none());
+
+        assert_eq!(Tag::STORAGE, StorageHint::Sparse);
+        assert_eq!(Tag::type_name(), "tag");
+        assert!(Tag::default_value().is_some());
+    }
+
+    #[test]
+    fn derived_component_metadata() {
+        #[derive(Component)]
+        #[component(crate = "crate")]
+        struct Plain;
+
+        #[derive(Component, Default)]
+        #[component(crate = "crate", name = "marker", storage = "sparse", default)]
+        struct Marker;
+
+        #[derive(Component)]
+        #[component(crate = "crate", name = "handle")]
+        struct Handle<T: Send + Sync + 'static>(T);
+
+        assert_eq!(Plain::STORAGE, StorageHint::Dense);
+        assert_eq!(Plain::type_name(), concat!(module_path!(), "::Plain"));
+        assert!(Plain::default_value().is_none());
+
+        assert_eq!(Marker::STORAGE, StorageHint::Sparse);
+        assert_eq!(Marker::type_name(), "marker");
+        assert!(Marker::default_value().is_some());
+
+        assert_eq!(Handle::<u32>::type_name(), "handle");
+    }
+
+    #[derive(Debug, PartialEq, Component)]
+    #[component(crate = "crate", storage = "sparse")]
+    struct Stun(u32);
+
+    #[test]
+    fn sparse_components() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        let entities = world.spawn_batch((0..4).map(|a| (Foo { a },)));
+        let group = world.entities[entities[0].id as usize].group;
+
+        world.add_component(entities[1], Tag);
+        world.add_component(entities[2], Tag);
+        world.add_component(entities[2], Stun(2));
+        world.add_component(entities[3], Stun(3));
+        assert!(entities.iter().all(|e| world.entities[e.id as usize].group == group));
+
+        let tagged = world.query_ref_filtered::<&Foo, With<Tag>>().map(|foo| foo.a).collect::<Vec<_>>();
+        assert_eq!(tagged, vec![1, 2]);
+        let untagged = world.query_ref_filtered::<&Foo, Not<Tag>>().map(|foo| foo.a).collect::<Vec<_>>();
+        assert_eq!(untagged, vec![0, 3]);
+
+        for (foo, stun) in world.query::<(&Foo, &mut Stun)>() {
+            stun.0 += foo.a as u32;
+        }
+        assert_eq!(world.get::<Stun>(entities[2]), Ok(&Stun(4)));
+        assert_eq!(world.get::<Stun>(entities[0]), Err(LookupError::MissingComponent));
+        let stuns = world
+            .query_ref::<(Entity, Option<&Stun>)>()
+            .map(|(_, stun)| stun.map(|s| s.0))
+            .collect::<Vec<_>>();
+        assert_eq!(stuns, vec![None, None, Some(4), Some(6)]);
+
+        assert_eq!(world.remove_component::<Stun>(entities[2]), Some(Stun(4)));
+        assert_eq!(world.remove_component::<Stun>(entities[2]), None);
+        assert_eq!(world.get::<Stun>(entities[3]), Ok(&Stun(6)));
+
+        world.free_entities(&entities[1..2]);
+        let replacement = world.spawn_batch(vec![(Foo { a: 5 },)]);
+        assert_eq!(replacement[0].id, entities[1].id);
+        assert!(world.get::<Tag>(replacement[0]).is_err());
+        assert_eq!(world.query_ref_filtered::<&Foo, With<Tag>>().count(), 1);
+    }
+
+    #[test]
+    fn spawn_sparse_components() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Tag>();
+        world.define_component::<Stun>();
+        let entities = world.spawn_batch((0..3).map(|a| (Foo { a }, Stun(a as u32), Tag)));
+        let group = world.entities[entities[0].id as usize].group;
+        assert_eq!(world.group_storages[&group].components.len(), 2);
+
+        let mut commands = world.commands();
+        let mut reserved = [Entity::INVALID; 2];
+        commands.create_entities::<(Foo, Stun)>(&mut reserved, (vec![Foo { a: 3 }, Foo { a: 4 }], vec![Stun(3), Stun(4)]));
+        world.apply(commands);
+        assert_eq!(world.entities[reserved[0].id as usize].group, group);
+
+        let stuns = world
+            .query_ref::<(&Foo, &Stun)>()
+            .map(|(foo, stun)| (foo.a, stun.0))
+            .collect::<Vec<_>>();
+        assert_eq!(stuns, vec![(0, 0), (1, 1), (2, 2), (3, 3), (4, 4)]);
+        assert_eq!(world.query_ref_filtered::<&Foo, With<Tag>>().count(), 3);
+        assert_eq!(world.get::<Stun>(reserved[1]), Ok(&Stun(4)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Changes of sparse components aren't tracked")]
+    fn changed_sparse_component() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.define_component::<Stun>();
+        world.query_ref_filtered::<&Foo, Changed<Stun>>().count();
+    }
+
+    #[test]
+    #[should_panic(expected = "Chunk queries can't access sparse components")]
+    fn sparse_chunk_query() {
+        let mut world = World::new();
+        world.define_component::<Tag>();
+        world.query_chunks_filtered::<&Foo, With<Tag>>();
+    }
+
+    #[derive(Debug, Component, tanya_ecs_derive::Reflect)]
+    #[component(crate = "crate")]
+    #[reflect(crate = "crate")]
+    struct Transform {
+        pos: [f32; 2],
+        scale: f32,
+        parent: crate::Entity,
+        // Doesn't implement `Reflect`.
+        #[reflect(skip)]
+        cache: Vec<u8>,
+    }
+    impl Component for u32 {
+        fn type_name() -> &'static str {
+            "u32"
+        }
+    }
+
+    impl Reflect for [f32; 2] {
+        fn fields(&self) -> &'static [FieldInfo] {
+            &[]
+        }
+
+        fn field(&self, _: &str) -> Option<&Reflect> {
+            None
+        }
+
+        fn field_mut(&mut self, _: &str) -> Option<&mut Reflect> {
+            None
+        }
+
+        fn as_any(&self) -> &::std::any::Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut ::std::any::Any {
+            self
+        }
+    }
+
+    #[test]
+    fn derived_reflect() {
+        #[derive(tanya_ecs_derive::Reflect)]
+        #[reflect(crate = "crate")]
+        struct Span(u32, #[reflect(skip)] Vec<u8>, ::std::string::String);
+
+        let transform = Transform {
+            pos: [1.0, 2.0],
+            scale: 1.0,
+            parent: Entity::INVALID,
+            cache: Vec::new(),
+        };
+        let transform: &Reflect = &transform;
+        // Field types are spelled as in the struct definition.
+        assert_eq!(
+            transform.fields(),
+            &[
+                FieldInfo { name: "pos", type_name: "[f32; 2]" },
+                FieldInfo { name: "scale", type_name: "f32" },
+                FieldInfo { name: "parent", type_name: "crate::Entity" },
+            ]
+        );
+        assert_eq!(transform.get::<[f32; 2]>("pos"), Some(&[1.0, 2.0]));
+        assert!(transform.field("cache").is_none());
+
+        let mut span = Span(1, vec![0], "span".into());
+        {
+            let span: &mut Reflect = &mut span;
+            assert_eq!(
+                span.fields(),
+                &[
+                    FieldInfo { name: "0", type_name: "u32" },
+                    FieldInfo { name: "2", type_name: "::std::string::String" },
+                ]
+            );
+            assert!(span.field("1").is_none());
+            assert_eq!(span.set("0", 3u32), Ok(()));
+            assert_eq!(span.get::<String>("2").map(|s| &s[..]), Some("span"));
+        }
+        assert_eq!(span.0, 3);
+    }
+
+    #[test]
+    fn reflect_components() {
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.register_reflect::<Transform>();
+        world.register_reflect::<u32>();
+
+        let transform = Transform {
+            pos: [0.0; 2],
+            scale: 1.0,
+            parent: Entity::INVALID,
+            cache: Vec::new(),
+        };
+        let entities = world.spawn_batch(vec![(Foo { a: 0 }, transform, 5u32)]);
+
+        {
+            let components = world.reflect(entities[0]).unwrap();
+            let names = components.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+            assert_eq!(names, vec![Transform::type_name(), u32::type_name()]);
+            assert_eq!(components[0].1.fields().len(), 3);
+            assert_eq!(components[0].1.get::<f32>("scale"), Some(&1.0));
+            assert_eq!(components[1].1.downcast_ref::<u32>(), Some(&5));
+        }
+
+        let transform = world.reflect_component_mut(entities[0], Transform::type_name()).unwrap();
+        assert!(transform.set("scale", 2.0f32).is_ok());
+        assert_eq!(transform.set("scale", 2u32), Err(2));
+        assert_eq!(transform.set("pos", 1.0f32), Err(1.0));
+        assert_eq!(world.get::<Transform>(entities[0]).unwrap().scale, 2.0);
+        assert_eq!(world.get::<Transform>(entities[0]).unwrap().pos, [0.0; 2]);
+
+        assert_eq!(
+            world.reflect_component(entities[0], "foo").err(),
+            Some(LookupError::MissingComponent)
+        );
+    }
+
+    #[test]
+    fn scene_roundtrip() {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, Serialize, Deserialize, Component)]
+        #[component(crate = "crate")]
+        struct Link {
+            target: Entity,
+            weight: u32,
+        }
+
+        let mut world = World::new();
+        world.define_component::<Foo>();
+        world.register_serialize::<Link>();
+
+        let entities = world.spawn_batch((0..3).map(|i| (Foo { a: i },)));
+        world.add_component(entities[0], Link { target: entities[1], weight: 1 });
+        world.add_component(entities[1], Link { target: entities[2], weight: 2 });
+        world.free_entities(&entities[2..]);
+
+        let check = |world: &World, loaded: &[Entity]| {
+            assert_eq!(loaded.len(), 2);
+            let links = loaded
+                .iter()
+                .map(|entity| world.get::<Link>(*entity).unwrap())
+                .collect::<Vec<_>>();
+            let first = links.iter().find(|link| link.weight == 1).unwrap();
+            let second = links.iter().find(|link| link.weight == 2).unwrap();
+            assert_eq!(world.get::<Link>(first.target).unwrap().weight, 2);
+            assert!(!world.is_alive(second.target));
+            assert!(world.get::<Foo>(loaded[0]).is_err());
+        };
+
+        let mut binary = World::new();
+        binary.register_serialize::<Link>();
+        let loaded = binary.load_binary(&world.save_binary().unwrap()).unwrap();
+        check(&binary, &loaded);
+
+        let mut ron = World::new();
+        ron.register_serialize::<Link>();
+        let loaded = ron.load_ron(&world.save_ron().unwrap()).unwrap();
+        check(&ron, &loaded);
+
+        let mut json = World::new();
+        json.register_serialize::<Link>();
+        let loaded = json.load_json(&world.save_json().unwrap()).unwrap();
+        check(&json, &loaded);
+
+        match World::new().load_json(&world.save_json().unwrap()) {
+            Err(SceneError::UnknownComponent(ref name)) if name.as_str() == Link::type_name() => (),
+            _ => panic!("expected unknown component"),
+        }
+        match json.load_json(r#"{"version":0,"components":[],"entities":[]}"#) {
+            Err(SceneError::Version(0)) => (),
+            _ => panic!("expected version mismatch"),
+        }
+
+        // Loaded entities are created directly in the group of their components.
+        assert_eq!(json.group_storages.len(), 1);
+
+        // Entities after a malformed one aren't created and their ids are reused.
+        let mut partial = World::new();
+        partial.define_component::<Foo>();
+        partial.register_serialize::<Link>();
+        let malformed = format!(
+            r#"{{"version":1,"components":["{}"],"entities":[[],[[1,null]],[]]}}"#,
+            Link::type_name()
+        );
+        match partial.load_json(&malformed) {
+            Err(SceneError::Format(_)) => (),
+            _ => panic!("expected malformed scene"),
+        }
+        assert_eq!(partial.query::<Entity>().count(), 1);
+        let spawned = partial.spawn_batch((0..2).map(|a| (Foo { a },)));
+        assert_eq!(spawned.iter().map(|entity| entity.id).collect::<Vec<_>>(), vec![1, 2]);
+    }
 }
@@ -0,0 +1,698 @@
+//! Queries over all groups containing a set of components.
+//!
+//! ```ignore
+//! for (entity, pos, vel) in world.query::<(Entity, &mut Pos, &Vel)>() {
+//!     // ..
+//! }
+//!
+//! // Skip entities with a `Frozen` component, only visit `Player`s.
+//! world.query_filtered::<(&mut Pos, Option<&Vel>), (With<Player>, Not<Frozen>)>();
+//!
+//! // Only visit chunks whose `Pos` changed since the last run of the system.
+//! let tick = world.increment_tick();
+//! world.query_since::<(Entity, &Pos), Changed<Pos>>(self.last_run);
+//! self.last_run = tick;
+//!
+//! // Sparse components are checked per entity.
+//! world.query_filtered::<&mut Pos, With<Selected>>();
+//! ```
+
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::slice;
+
+use crate::sparse::{SparseSet, SparseStorage};
+use crate::{
+    ChunkId, ChunkPtr, Component, ComponentId, Entity, EntityId, GroupStorage, SlotId, StorageHint, Tick,
+    ENTITY_COMP_ID,
+};
+
+/// World data required for resolving queries.
+pub struct QueryContext<'a> {
+    pub(crate) comp_map: &'a HashMap<TypeId, ComponentId>,
+    pub(crate) sparse_sets: &'a HashMap<ComponentId, Box<SparseStorage>>,
+    /// Tick stamped onto chunks on mutable access.
+    pub(crate) tick: Tick,
+    /// Tick compared against by change filters.
+    pub(crate) since: Tick,
+    /// Shared component value index chunks have to match.
+    pub(crate) shared: Option<(ComponentId, usize)>,
+}
+
+impl<'a> QueryContext<'a> {
+    fn component<C: Component>(&self) -> Option<ComponentId> {
+        self.comp_map.get(&TypeId::of::<C>()).cloned()
+    }
+
+    fn chunks<C: Component>(&self, group: &'a GroupStorage) -> Option<&'a [ChunkPtr]> {
+        self.component::<C>()
+            .and_then(|id| group.comp_chunks.get(&id))
+            .map(|chunks| &chunks[..])
+    }
+
+    fn chunk<'g, C: Component>(&self, group: &'g GroupStorage, chunk: ChunkId) -> Option<&'g ChunkPtr> {
+        self.component::<C>()
+            .and_then(|id| group.comp_chunks.get(&id))
+            .map(|chunks| &chunks[chunk])
+    }
+
+    /// Sparse set of `C`, `None` for dense or undefined components.
+    fn sparse<C: Component>(&self) -> Option<&'a SparseSet<C>> {
+        if C::STORAGE != StorageHint::Sparse {
+            return None;
+        }
+
+        self.component::<C>()
+            .and_then(|id| self.sparse_sets.get(&id))
+            .and_then(|set| set.as_any().downcast_ref())
+    }
+
+    /// Check if the entity of a slot has the sparse component `C`.
+    ///
+    /// Dense components are checked on group level and always pass.
+    fn sparse_contains<C: Component>(&self, group: &GroupStorage, chunk: ChunkId, slot: SlotId) -> bool {
+        match self.sparse::<C>() {
+            Some(set) => set.contains(unsafe { slot_entity(&group.comp_chunks[&ENTITY_COMP_ID], chunk, slot) }),
+            None => true,
+        }
+    }
+
+    fn contains<C: Component>(&self, group: &GroupStorage) -> bool {
+        self.component::<C>()
+            .map(|id| group.comp_chunks.contains_key(&id))
+            .unwrap_or(false)
+    }
+
+    /// Check for a per-slot or shared component.
+    fn has<C: Component>(&self, group: &GroupStorage) -> bool {
+        self.component::<C>()
+            .map(|id| group.comp_chunks.contains_key(&id) || group.shared.contains(&id))
+            .unwrap_or(false)
+    }
+
+    fn matches_shared(&self, group: &GroupStorage, chunk: ChunkId) -> bool {
+        match self.shared {
+            Some((id, index)) => group
+                .shared
+                .iter()
+                .position(|shared| *shared == id)
+                .map(|pos| group.chunk_data[chunk].shared[pos] == index)
+                .unwrap_or(false),
+            None => true,
+        }
+    }
+}
+
+unsafe fn slot_entity(entities: &[ChunkPtr], chunk: ChunkId, slot: SlotId) -> EntityId {
+    (*(entities[chunk].ptr as *const Entity).add(slot)).id
+}
+
+/// Components of a group accessed by a query, see `StorageHint`.
+pub enum Column<'a, C> {
+    /// Chunks of the component.
+    Dense(&'a [ChunkPtr]),
+    /// Sparse set of the component and the entity chunks of the group.
+    Sparse(&'a SparseSet<C>, &'a [ChunkPtr]),
+}
+
+impl<'a, C: Component> Column<'a, C> {
+    fn new(ctx: &QueryContext<'a>, group: &'a GroupStorage) -> Option<Self> {
+        match ctx.sparse::<C>() {
+            Some(set) => Some(Column::Sparse(set, &group.comp_chunks[&ENTITY_COMP_ID])),
+            None => ctx.chunks::<C>(group).map(Column::Dense),
+        }
+    }
+
+    /// Pointer to the component of a slot, `None` if the entity doesn't have the sparse component.
+    unsafe fn slot(&self, chunk: ChunkId, slot: SlotId) -> Option<*mut C> {
+        match *self {
+            Column::Dense(chunks) => Some((chunks[chunk].ptr as *mut C).add(slot)),
+            Column::Sparse(set, entities) => set.get_ptr(slot_entity(entities, chunk, slot)),
+        }
+    }
+
+    fn contains(&self, chunk: ChunkId, slot: SlotId) -> bool {
+        match *self {
+            Column::Dense(_) => true,
+            Column::Sparse(set, entities) => set.contains(unsafe { slot_entity(entities, chunk, slot) }),
+        }
+    }
+
+    /// Pointer to the first component of a chunk.
+    fn chunk(&self, chunk: ChunkId) -> *mut C {
+        match *self {
+            Column::Dense(chunks) => chunks[chunk].ptr as *mut C,
+            Column::Sparse(..) => panic!("Sparse components can't be fetched by chunk"),
+        }
+    }
+
+    fn mark_changed(&self, chunk: ChunkId, tick: Tick) {
+        if let Column::Dense(chunks) = *self {
+            chunks[chunk].mark_changed(tick);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Data fetched for each entity of a query.
+pub trait Query<'a> {
+    type Item;
+    type State;
+    type Chunk;
+
+    /// Collect the component types accessed by the query.
+    fn access(access: &mut Vec<(TypeId, Access)>);
+    /// Check if the group contains all required components.
+    fn matches(ctx: &QueryContext<'a>, group: &GroupStorage) -> bool;
+    fn state(ctx: &QueryContext<'a>, group: &'a GroupStorage) -> Self::State;
+    /// Check if the entity of a slot has all required sparse components.
+    unsafe fn matches_slot(_state: &Self::State, _chunk: ChunkId, _slot: SlotId) -> bool {
+        true
+    }
+    unsafe fn fetch(ctx: &QueryContext<'a>, state: &Self::State, chunk: ChunkId, slot: SlotId) -> Self::Item;
+    /// Fetch the first `len` slots of a chunk as slices.
+    unsafe fn fetch_chunk(ctx: &QueryContext<'a>, state: &Self::State, chunk: ChunkId, len: usize) -> Self::Chunk;
+    /// Check if sparse components are accessed, which can't be fetched by chunk.
+    fn sparse() -> bool {
+        false
+    }
+}
+
+/// Queries without mutable component access, which can run on a shared world.
+pub trait ReadOnlyQuery<'a>: Query<'a> {}
+
+/// Restricts the groups visited by a query without fetching data.
+pub trait Filter {
+    fn matches(ctx: &QueryContext, group: &GroupStorage) -> bool;
+    /// Check if a chunk of a matching group needs to be visited.
+    fn matches_chunk(_ctx: &QueryContext, _group: &GroupStorage, _chunk: ChunkId) -> bool {
+        true
+    }
+    /// Check if a slot of a matching chunk needs to be visited.
+    fn matches_slot(_ctx: &QueryContext, _group: &GroupStorage, _chunk: ChunkId, _slot: SlotId) -> bool {
+        true
+    }
+    /// Check if sparse components are filtered, which requires checking each slot.
+    fn sparse() -> bool {
+        false
+    }
+    /// Check if changes of sparse components are filtered, which aren't tracked.
+    fn untracked() -> bool {
+        false
+    }
+}
+
+/// Only match groups containing the component `C`.
+pub struct With<C>(PhantomData<C>);
+
+/// Only match groups not containing the component `C`.
+pub struct Not<C>(PhantomData<C>);
+
+/// Only match chunks in which `C` was added or mutably accessed after the query tick.
+///
+/// Not supported for sparse components.
+pub struct Changed<C>(PhantomData<C>);
+
+/// Only match chunks into which `C` was added after the query tick.
+///
+/// Not supported for sparse components.
+pub struct Added<C>(PhantomData<C>);
+
+impl<'a> Query<'a> for Entity {
+    type Item = Entity;
+    type State = &'a [ChunkPtr];
+    type Chunk = &'a [Entity];
+
+    fn access(access: &mut Vec<(TypeId, Access)>) {
+        access.push((TypeId::of::<Entity>(), Access::Read));
+    }
+
+    fn matches(_: &QueryContext<'a>, _: &GroupStorage) -> bool {
+        true
+    }
+
+    fn state(_: &QueryContext<'a>, group: &'a GroupStorage) -> Self::State {
+        &group.comp_chunks[&ENTITY_COMP_ID]
+    }
+
+    unsafe fn fetch(_: &QueryContext<'a>, state: &Self::State, chunk: ChunkId, slot: SlotId) -> Self::Item {
+        *(state[chunk].ptr as *const Entity).add(slot)
+    }
+
+    unsafe fn fetch_chunk(_: &QueryContext<'a>, state: &Self::State, chunk: ChunkId, len: usize) -> Self::Chunk {
+        slice::from_raw_parts(state[chunk].ptr as *const Entity, len)
+    }
+}
+
+impl<'a, C: Component> Query<'a> for &'a C {
+    type Item = &'a C;
+    type State = Column<'a, C>;
+    type Chunk = &'a [C];
+
+    fn access(access: &mut Vec<(TypeId, Access)>) {
+        access.push((TypeId::of::<C>(), Access::Read));
+    }
+
+    fn matches(ctx: &QueryContext<'a>, group: &GroupStorage) -> bool {
+        ctx.sparse::<C>().is_some() || ctx.contains::<C>(group)
+    }
+
+    fn state(ctx: &QueryContext<'a>, group: &'a GroupStorage) -> Self::State {
+        Column::new(ctx, group).unwrap()
+    }
+
+    unsafe fn matches_slot(state: &Self::State, chunk: ChunkId, slot: SlotId) -> bool {
+        state.contains(chunk, slot)
+    }
+
+    unsafe fn fetch(_: &QueryContext<'a>, state: &Self::State, chunk: ChunkId, slot: SlotId) -> Self::Item {
+        &*state.slot(chunk, slot).unwrap()
+    }
+
+    unsafe fn fetch_chunk(_: &QueryContext<'a>, state: &Self::State, chunk: ChunkId, len: usize) -> Self::Chunk {
+        slice::from_raw_parts(state.chunk(chunk), len)
+    }
+
+    fn sparse() -> bool {
+        C::STORAGE == StorageHint::Sparse
+    }
+}
+
+impl<'a, C: Component> Query<'a> for &'a mut C {
+    type Item = &'a mut C;
+    type State = Column<'a, C>;
+    type Chunk = &'a mut [C];
+
+    fn access(access: &mut Vec<(TypeId, Access)>) {
+        access.push((TypeId::of::<C>(), Access::Write));
+    }
+
+    fn matches(ctx: &QueryContext<'a>, group: &GroupStorage) -> bool {
+        ctx.sparse::<C>().is_some() || ctx.contains::<C>(group)
+    }
+
+    fn state(ctx: &QueryContext<'a>, group: &'a GroupStorage) -> Self::State {
+        Column::new(ctx, group).unwrap()
+    }
+
+    unsafe fn matches_slot(state: &Self::State, chunk: ChunkId, slot: SlotId) -> bool {
+        state.contains(chunk, slot)
+    }
+
+    unsafe fn fetch(ctx: &QueryContext<'a>, state: &Self::State, chunk: ChunkId, slot: SlotId) -> Self::Item {
+        state.mark_changed(chunk, ctx.tick);
+        &mut *state.slot(chunk, slot).unwrap()
+    }
+
+    unsafe fn fetch_chunk(ctx: &QueryContext<'a>, state: &Self::State, chunk: ChunkId, len: usize) -> Self::Chunk {
+        let ptr = state.chunk(chunk);
+        state.mark_changed(chunk, ctx.tick);
+        slice::from_raw_parts_mut(ptr, len)
+    }
+
+    fn sparse() -> bool {
+        C::STORAGE == StorageHint::Sparse
+    }
+}
+
+impl<'a, C: Component> Query<'a> for Option<&'a C> {
+    type Item = Option<&'a C>;
+    type State = Option<Column<'a, C>>;
+    type Chunk = Option<&'a [C]>;
+
+    fn access(access: &mut Vec<(TypeId, Access)>) {
+        access.push((TypeId::of::<C>(), Access::Read));
+    }
+
+    fn matches(_: &QueryContext<'a>, _: &GroupStorage) -> bool {
+        true
+    }
+
+    fn state(ctx: &QueryContext<'a>, group: &'a GroupStorage) -> Self::State {
+        Column::new(ctx, group)
+    }
+
+    unsafe fn fetch(_: &QueryContext<'a>, state: &Self::State, chunk: ChunkId, slot: SlotId) -> Self::Item {
+        state.as_ref().and_then(|column| column.slot(chunk, slot)).map(|ptr| &*ptr)
+    }
+
+    unsafe fn fetch_chunk(_: &QueryContext<'a>, state: &Self::State, chunk: ChunkId, len: usize) -> Self::Chunk {
+        state.as_ref().map(|column| slice::from_raw_parts(column.chunk(chunk), len))
+    }
+
+    fn sparse() -> bool {
+        C::STORAGE == StorageHint::Sparse
+    }
+}
+
+impl<'a, C: Component> Query<'a> for Option<&'a mut C> {
+    type Item = Option<&'a mut C>;
+    type State = Option<Column<'a, C>>;
+    type Chunk = Option<&'a mut [C]>;
+
+    fn access(access: &mut Vec<(TypeId, Access)>) {
+        access.push((TypeId::of::<C>(), Access::Write));
+    }
+
+    fn matches(_: &QueryContext<'a>, _: &GroupStorage) -> bool {
+        true
+    }
+
+    fn state(ctx: &QueryContext<'a>, group: &'a GroupStorage) -> Self::State {
+        Column::new(ctx, group)
+    }
+
+    unsafe fn fetch(ctx: &QueryContext<'a>, state: &Self::State, chunk: ChunkId, slot: SlotId) -> Self::Item {
+        state.as_ref().and_then(|column| {
+            column.mark_changed(chunk, ctx.tick);
+            column.slot(chunk, slot).map(|ptr| &mut *ptr)
+        })
+    }
+
+    unsafe fn fetch_chunk(ctx: &QueryContext<'a>, state: &Self::State, chunk: ChunkId, len: usize) -> Self::Chunk {
+        state.as_ref().map(|column| {
+            let ptr = column.chunk(chunk);
+            column.mark_changed(chunk, ctx.tick);
+            slice::from_raw_parts_mut(ptr, len)
+        })
+    }
+
+    fn sparse() -> bool {
+        C::STORAGE == StorageHint::Sparse
+    }
+}
+
+impl<'a> ReadOnlyQuery<'a> for Entity {}
+impl<'a, C: Component> ReadOnlyQuery<'a> for &'a C {}
+impl<'a, C: Component> ReadOnlyQuery<'a> for Option<&'a C> {}
+
+impl<C: Component> Filter for With<C> {
+    fn matches(ctx: &QueryContext, group: &GroupStorage) -> bool {
+        ctx.sparse::<C>().is_some() || ctx.has::<C>(group)
+    }
+
+    fn matches_slot(ctx: &QueryContext, group: &GroupStorage, chunk: ChunkId, slot: SlotId) -> bool {
+        ctx.sparse_contains::<C>(group, chunk, slot)
+    }
+
+    fn sparse() -> bool {
+        C::STORAGE == StorageHint::Sparse
+    }
+}
+
+impl<C: Component> Filter for Not<C> {
+    fn matches(ctx: &QueryContext, group: &GroupStorage) -> bool {
+        ctx.sparse::<C>().is_some() || !ctx.has::<C>(group)
+    }
+
+    fn matches_slot(ctx: &QueryContext, group: &GroupStorage, chunk: ChunkId, slot: SlotId) -> bool {
+        ctx.sparse::<C>().is_none() || !ctx.sparse_contains::<C>(group, chunk, slot)
+    }
+
+    fn sparse() -> bool {
+        C::STORAGE == StorageHint::Sparse
+    }
+}
+
+impl<C: Component> Filter for Changed<C> {
+    fn matches(ctx: &QueryContext, group: &GroupStorage) -> bool {
+        ctx.contains::<C>(group)
+    }
+
+    fn matches_chunk(ctx: &QueryContext, group: &GroupStorage, chunk: ChunkId) -> bool {
+        ctx.chunk::<C>(group, chunk)
+            .map(|chunk| chunk.changed() > ctx.since)
+            .unwrap_or(false)
+    }
+
+    fn untracked() -> bool {
+        C::STORAGE == StorageHint::Sparse
+    }
+}
+
+impl<C: Component> Filter for Added<C> {
+    fn matches(ctx: &QueryContext, group: &GroupStorage) -> bool {
+        ctx.contains::<C>(group)
+    }
+
+    fn matches_chunk(ctx: &QueryContext, group: &GroupStorage, chunk: ChunkId) -> bool {
+        ctx.chunk::<C>(group, chunk)
+            .map(|chunk| chunk.added() > ctx.since)
+            .unwrap_or(false)
+    }
+
+    fn untracked() -> bool {
+        C::STORAGE == StorageHint::Sparse
+    }
+}
+
+macro_rules! impl_query {
+    ($($ty:ident: $idx:tt),*) => {
+        impl<'a, $($ty: Query<'a>),*> Query<'a> for ($($ty,)*) {
+            type Item = ($($ty::Item,)*);
+            type State = ($($ty::State,)*);
+            type Chunk = ($($ty::Chunk,)*);
+
+            fn access(access: &mut Vec<(TypeId, Access)>) {
+                $($ty::access(access);)*
+            }
+
+            fn matches(ctx: &QueryContext<'a>, group: &GroupStorage) -> bool {
+                $($ty::matches(ctx, group))&&*
+            }
+
+            fn state(ctx: &QueryContext<'a>, group: &'a GroupStorage) -> Self::State {
+                ($($ty::state(ctx, group),)*)
+            }
+
+            unsafe fn matches_slot(state: &Self::State, chunk: ChunkId, slot: SlotId) -> bool {
+                $($ty::matches_slot(&state.$idx, chunk, slot))&&*
+            }
+
+            unsafe fn fetch(ctx: &QueryContext<'a>, state: &Self::State, chunk: ChunkId, slot: SlotId) -> Self::Item {
+                ($($ty::fetch(ctx, &state.$idx, chunk, slot),)*)
+            }
+
+            unsafe fn fetch_chunk(ctx: &QueryContext<'a>, state: &Self::State, chunk: ChunkId, len: usize) -> Self::Chunk {
+                ($($ty::fetch_chunk(ctx, &state.$idx, chunk, len),)*)
+            }
+
+            fn sparse() -> bool {
+                $($ty::sparse())||*
+            }
+        }
+
+        impl<'a, $($ty: ReadOnlyQuery<'a>),*> ReadOnlyQuery<'a> for ($($ty,)*) {}
+
+        impl<$($ty: Filter),*> Filter for ($($ty,)*) {
+            fn matches(ctx: &QueryContext, group: &GroupStorage) -> bool {
+                $($ty::matches(ctx, group))&&*
+            }
+
+            fn matches_chunk(ctx: &QueryContext, group: &GroupStorage, chunk: ChunkId) -> bool {
+                $($ty::matches_chunk(ctx, group, chunk))&&*
+            }
+
+            fn matches_slot(ctx: &QueryContext, group: &GroupStorage, chunk: ChunkId, slot: SlotId) -> bool {
+                $($ty::matches_slot(ctx, group, chunk, slot))&&*
+            }
+
+            fn sparse() -> bool {
+                $($ty::sparse())||*
+            }
+
+            fn untracked() -> bool {
+                $($ty::untracked())||*
+            }
+        }
+    };
+}
+
+impl Filter for () {
+    fn matches(_: &QueryContext, _: &GroupStorage) -> bool {
+        true
+    }
+}
+
+impl_query!(A: 0);
+impl_query!(A: 0, B: 1);
+impl_query!(A: 0, B: 1, C: 2);
+impl_query!(A: 0, B: 1, C: 2, D: 3);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9, K: 10);
+impl_query!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9, K: 10, L: 11);
+
+pub struct QueryIter<'a, Q: Query<'a>, F> {
+    ctx: QueryContext<'a>,
+    groups: Vec<&'a GroupStorage>,
+    cur_group: usize,
+    cur: Option<(Q::State, &'a GroupStorage)>,
+    cur_chunk: usize,
+    cur_slot: usize,
+    _marker: PhantomData<F>,
+}
+
+/// Collect the component types accessed by a query.
+///
+/// Panics if a component is accessed mutably and additionally by another element
+/// of the query.
+pub fn validate_access<'a, Q: Query<'a>>() -> Vec<(TypeId, Access)> {
+    let mut access = Vec::new();
+    Q::access(&mut access);
+    access.sort_by_key(|(ty, _)| *ty);
+    for pair in access.windows(2) {
+        if pair[0].0 == pair[1].0 && (pair[0].1 == Access::Write || pair[1].1 == Access::Write) {
+            panic!(
+                "Component ({:?}) accessed mutably while also accessed in the same query",
+                pair[0].0
+            );
+        }
+    }
+    access.dedup();
+    access
+}
+
+/// Select the groups visited by a query, validating the requested component access.
+fn matching_groups<'a, Q, F, I>(ctx: &QueryContext<'a>, groups: I) -> Vec<&'a GroupStorage>
+where
+    Q: Query<'a>,
+    F: Filter,
+    I: Iterator<Item = &'a GroupStorage>,
+{
+    validate_access::<Q>();
+
+    groups
+        .filter(|group| Q::matches(ctx, group) && F::matches(ctx, group))
+        .collect()
+}
+
+/// Check if a chunk of a matching group passes the filter and the shared component value.
+fn matches_chunk<F: Filter>(ctx: &QueryContext, group: &GroupStorage, chunk: ChunkId) -> bool {
+    F::matches_chunk(ctx, group, chunk) && ctx.matches_shared(group, chunk)
+}
+
+impl<'a, Q: Query<'a>, F: Filter> QueryIter<'a, Q, F> {
+    pub(crate) fn new<I>(ctx: QueryContext<'a>, groups: I) -> Self
+    where
+        I: Iterator<Item = &'a GroupStorage>,
+    {
+        assert!(!F::untracked(), "Changes of sparse components aren't tracked");
+        let groups = matching_groups::<Q, F, _>(&ctx, groups);
+
+        QueryIter {
+            ctx,
+            groups,
+            cur_group: 0,
+            cur: None,
+            cur_chunk: 0,
+            cur_slot: 0,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, Q: Query<'a>, F: Filter> Iterator for QueryIter<'a, Q, F> {
+    type Item = Q::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((ref state, group)) = self.cur {
+                let chunk_data = &group.chunk_data;
+                while self.cur_chunk < chunk_data.len()
+                    && (self.cur_slot >= chunk_data[self.cur_chunk].len
+                        || (self.cur_slot == 0 && !matches_chunk::<F>(&self.ctx, group, self.cur_chunk)))
+                {
+                    self.cur_slot = 0;
+                    self.cur_chunk += 1;
+                }
+
+                if self.cur_chunk < chunk_data.len() {
+                    let (chunk, slot) = (self.cur_chunk, self.cur_slot);
+                    self.cur_slot += 1;
+                    // Sparse components are only known per slot.
+                    if unsafe { !Q::matches_slot(state, chunk, slot) } || !F::matches_slot(&self.ctx, group, chunk, slot) {
+                        continue;
+                    }
+                    return Some(unsafe { Q::fetch(&self.ctx, state, chunk, slot) });
+                }
+            }
+
+            let group = *self.groups.get(self.cur_group)?;
+            self.cur_group += 1;
+            self.cur = Some((Q::state(&self.ctx, group), group));
+            self.cur_chunk = 0;
+            self.cur_slot = 0;
+        }
+    }
+}
+
+/// Iterator over the non-empty chunks of all groups matching a query.
+///
+/// Each item contains slices of the live slots of a chunk.
+pub struct ChunkIter<'a, Q: Query<'a>, F> {
+    ctx: QueryContext<'a>,
+    groups: Vec<&'a GroupStorage>,
+    cur_group: usize,
+    cur: Option<(Q::State, &'a GroupStorage)>,
+    cur_chunk: usize,
+    _marker: PhantomData<F>,
+}
+
+impl<'a, Q: Query<'a>, F: Filter> ChunkIter<'a, Q, F> {
+    pub(crate) fn new<I>(ctx: QueryContext<'a>, groups: I) -> Self
+    where
+        I: Iterator<Item = &'a GroupStorage>,
+    {
+        assert!(
+            !Q::sparse() && !F::sparse(),
+            "Chunk queries can't access sparse components"
+        );
+        assert!(!F::untracked(), "Changes of sparse components aren't tracked");
+        let groups = matching_groups::<Q, F, _>(&ctx, groups);
+
+        ChunkIter {
+            ctx,
+            groups,
+            cur_group: 0,
+            cur: None,
+            cur_chunk: 0,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, Q: Query<'a>, F: Filter> Iterator for ChunkIter<'a, Q, F> {
+    type Item = Q::Chunk;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((ref state, group)) = self.cur {
+                while self.cur_chunk < group.chunk_data.len() {
+                    let chunk = self.cur_chunk;
+                    let len = group.chunk_data[chunk].len;
+                    self.cur_chunk += 1;
+
+                    if len > 0 && matches_chunk::<F>(&self.ctx, group, chunk) {
+                        return Some(unsafe { Q::fetch_chunk(&self.ctx, state, chunk, len) });
+                    }
+                }
+            }
+
+            let group = *self.groups.get(self.cur_group)?;
+            self.cur_group += 1;
+            self.cur = Some((Q::state(&self.ctx, group), group));
+            self.cur_chunk = 0;
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Runtime reflection of component fields for editors and inspectors.
+//!
+//! Components implementing `Reflect` (usually via `#[derive(Reflect)]`) and
+//! registered with `World::register_reflect` can be enumerated and edited
+//! by name without knowing their types.
+//!
+//! ```ignore
+//! world.register_reflect::<Transform>();
+//!
+//! for (name, component) in world.reflect_mut(entity)? {
+//!     for field in component.fields() {
+//!         println!("{}.{}: {}", name, field.name, field.type_name);
+//!     }
+//! }
+//! world.reflect_component_mut(entity, "game::Transform")?.set("scale", 2.0f32);
+//! ```
+
+use super::{ChunkPtr, Entity, SlotId};
+use std::any::Any;
+
+/// Name and type of a reflected field.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FieldInfo {
+    pub name: &'static str,
+    /// Type of the field as spelled in the struct definition, e.g. `[f32; 2]`.
+    ///
+    /// Paths aren't resolved, `String` and `::std::string::String` are different
+    /// names for the same type. Intended for display only, use `Reflect::get` or
+    /// `downcast_ref` to check the actual type.
+    pub type_name: &'static str,
+}
+
+/// Dynamic access to a value and its fields.
+pub trait Reflect: Any + Send + Sync {
+    /// Fields of the value, empty for primitive types.
+    fn fields(&self) -> &'static [FieldInfo];
+    fn field(&self, name: &str) -> Option<&Reflect>;
+    fn field_mut(&mut self, name: &str) -> Option<&mut Reflect>;
+    fn as_any(&self) -> &Any;
+    fn as_any_mut(&mut self) -> &mut Any;
+}
+
+impl Reflect {
+    pub fn downcast_ref<T: Reflect>(&self) -> Option<&T> {
+        self.as_any().downcast_ref()
+    }
+
+    pub fn downcast_mut<T: Reflect>(&mut self) -> Option<&mut T> {
+        self.as_any_mut().downcast_mut()
+    }
+
+    /// Read a field of type `T`.
+    pub fn get<T: Reflect>(&self, field: &str) -> Option<&T> {
+        self.field(field).and_then(|field| field.downcast_ref())
+    }
+
+    /// Overwrite a field of type `T`.
+    ///
+    /// Returns the value back if the field doesn't exist or has a different type.
+    pub fn set<T: Reflect>(&mut self, field: &str, value: T) -> Result<(), T> {
+        match self.field_mut(field).and_then(|field| field.downcast_mut::<T>()) {
+            Some(field) => {
+                *field = value;
+                Ok(())
+            }
+            None => Err(value),
+        }
+    }
+}
+
+macro_rules! impl_reflect_value {
+    ($($ty:ty),*) => {
+        $(
+            impl Reflect for $ty {
+                fn fields(&self) -> &'static [FieldInfo] {
+                    &[]
+                }
+
+                fn field(&self, _: &str) -> Option<&Reflect> {
+                    None
+                }
+
+                fn field_mut(&mut self, _: &str) -> Option<&mut Reflect> {
+                    None
+                }
+
+                fn as_any(&self) -> &Any {
+                    self
+                }
+
+                fn as_any_mut(&mut self) -> &mut Any {
+                    self
+                }
+            }
+        )*
+    };
+}
+
+impl_reflect_value!(bool, u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64, String, Entity);
+
+/// Type erased access to reflected components stored in chunks.
+#[derive(Copy, Clone)]
+pub(crate) struct ReflectEntry {
+    pub name: &'static str,
+    pub slot: unsafe fn(&ChunkPtr, SlotId) -> *mut Reflect,
+}
+
+pub(crate) unsafe fn reflect_slot<C: Reflect>(chunk: &ChunkPtr, slot: SlotId) -> *mut Reflect {
+    (chunk.ptr as *mut C).add(slot) as *mut Reflect
+}
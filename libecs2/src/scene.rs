@@ -0,0 +1,387 @@
+//! Saving and loading entities of a world.
+//!
+//! A scene stores all entities of a world in group order. Each entity lists its
+//! serializable components as pairs of an index into the component name table
+//! and the serialized component. Components are matched by their stable
+//! `Component::type_name` on load, independent of the component ids of the
+//! saving and loading world.
+//!
+//! `Entity` values inside components are written as the index of the referenced
+//! entity in the scene and remapped to the newly created entities on load.
+//! References to entities which aren't part of the scene become `Entity::INVALID`.
+//!
+//! ```ignore
+//! world.register_serialize::<Transform>();
+//! let data = world.save_ron()?;
+//!
+//! let mut world = World::new();
+//! world.register_serialize::<Transform>();
+//! let entities = world.load_ron(&data)?;
+//! ```
+
+use super::{
+    ChunkPtr, Component, ComponentId, Entity, EntityData, EntityId, Generation, SlotId, StorageHint, World,
+    ENTITY_COMP_ID,
+};
+use serde::de::{DeserializeOwned, Deserializer};
+use serde::ser::{self, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::ptr;
+
+/// Version of the scene format written by this crate.
+pub const SCENE_VERSION: u32 = 1;
+
+// Scene index of references to entities outside of the scene.
+const INVALID_INDEX: u32 = u32::max_value();
+
+#[derive(Debug)]
+pub enum SceneError {
+    /// The scene was written with an unsupported format version.
+    Version(u32),
+    /// The scene contains a component which isn't registered for serialization.
+    UnknownComponent(String),
+    /// The scene data is malformed.
+    Format(Box<Error + Send + Sync>),
+}
+
+impl SceneError {
+    fn format<E: Error + Send + Sync + 'static>(err: E) -> Self {
+        SceneError::Format(Box::new(err))
+    }
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SceneError::Version(version) => write!(
+                f,
+                "unsupported scene version {} (expected {})",
+                version, SCENE_VERSION
+            ),
+            SceneError::UnknownComponent(ref name) => write!(f, "unknown component `{}`", name),
+            SceneError::Format(ref err) => write!(f, "malformed scene: {}", err),
+        }
+    }
+}
+
+impl Error for SceneError {}
+
+#[derive(Serialize, Deserialize)]
+struct Scene<D> {
+    version: u32,
+    /// Names of the serialized components.
+    components: Vec<String>,
+    /// Components of each entity as index into the name table and data.
+    entities: Vec<Vec<(u32, D)>>,
+}
+
+/// Type erased serialization of a registered component.
+#[derive(Copy, Clone)]
+pub(crate) struct SerializeEntry {
+    id: ComponentId,
+    name: &'static str,
+    to_bytes: unsafe fn(&ChunkPtr, SlotId) -> Result<Vec<u8>, SceneError>,
+    to_value: unsafe fn(&ChunkPtr, SlotId) -> Result<Value, SceneError>,
+    from_bytes: fn(&[u8]) -> Result<Box<Any>, SceneError>,
+    from_value: fn(Value) -> Result<Box<Any>, SceneError>,
+    /// Move a deserialized component into an uninitialized slot.
+    write: unsafe fn(Box<Any>, &ChunkPtr, SlotId),
+}
+
+unsafe fn to_bytes<C: Serialize>(chunk: &ChunkPtr, slot: SlotId) -> Result<Vec<u8>, SceneError> {
+    bincode::serialize(&*(chunk.ptr as *const C).add(slot)).map_err(SceneError::format)
+}
+
+unsafe fn to_value<C: Serialize>(chunk: &ChunkPtr, slot: SlotId) -> Result<Value, SceneError> {
+    serde_json::to_value(&*(chunk.ptr as *const C).add(slot)).map_err(SceneError::format)
+}
+
+fn from_bytes<C>(data: &[u8]) -> Result<Box<Any>, SceneError>
+where
+    C: Component + DeserializeOwned,
+{
+    let component = bincode::deserialize::<C>(data).map_err(SceneError::format)?;
+    Ok(Box::new(component))
+}
+
+fn from_value<C>(data: Value) -> Result<Box<Any>, SceneError>
+where
+    C: Component + DeserializeOwned,
+{
+    let component = serde_json::from_value::<C>(data).map_err(SceneError::format)?;
+    Ok(Box::new(component))
+}
+
+unsafe fn write<C: Component>(component: Box<Any>, chunk: &ChunkPtr, slot: SlotId) {
+    let component = component.downcast::<C>().expect("Component type mismatch");
+    ptr::write((chunk.ptr as *mut C).add(slot), *component);
+}
+
+enum EntityMap {
+    /// Scene index of each saved entity.
+    Save(HashMap<EntityId, (Generation, u32)>),
+    /// Created entity for each scene index.
+    Load(Vec<Entity>),
+}
+
+thread_local! {
+    static ENTITY_MAP: RefCell<Option<EntityMap>> = RefCell::new(None);
+}
+
+/// Makes the entity map available to `Entity` (de)serialization while alive.
+struct EntityMapScope;
+
+impl EntityMapScope {
+    fn new(map: EntityMap) -> Self {
+        ENTITY_MAP.with(|cur| *cur.borrow_mut() = Some(map));
+        EntityMapScope
+    }
+}
+
+impl Drop for EntityMapScope {
+    fn drop(&mut self) {
+        ENTITY_MAP.with(|cur| *cur.borrow_mut() = None);
+    }
+}
+
+impl Serialize for Entity {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let index = ENTITY_MAP.with(|map| match *map.borrow() {
+            Some(EntityMap::Save(ref map)) => Some(match map.get(&self.id) {
+                Some((generation, index)) if *generation == self.generation => *index,
+                _ => INVALID_INDEX,
+            }),
+            _ => None,
+        });
+
+        match index {
+            Some(index) => serializer.serialize_u32(index),
+            None => Err(ser::Error::custom("entities can only be serialized as part of a scene")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Entity {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let index = u32::deserialize(deserializer)?;
+        ENTITY_MAP.with(|map| match *map.borrow() {
+            Some(EntityMap::Load(ref entities)) => Ok(entities
+                .get(index as usize)
+                .cloned()
+                .unwrap_or(Entity::INVALID)),
+            _ => Err(D::Error::custom("entities can only be deserialized as part of a scene")),
+        })
+    }
+}
+
+impl World {
+    /// Define a component and include it when saving and loading scenes.
+    pub fn register_serialize<C>(&mut self) -> ComponentId
+    where
+        C: Component + Serialize + DeserializeOwned,
+    {
+        assert_eq!(C::STORAGE, StorageHint::Dense, "Sparse components can't be serialized");
+        let id = self.define_component::<C>();
+        self.serialize.insert(
+            id,
+            SerializeEntry {
+                id,
+                name: C::type_name(),
+                to_bytes: to_bytes::<C>,
+                to_value: to_value::<C>,
+                from_bytes: from_bytes::<C>,
+                from_value: from_value::<C>,
+                write: write::<C>,
+            },
+        );
+        id
+    }
+
+    /// Save all entities in the compact binary format.
+    pub fn save_binary(&self) -> Result<Vec<u8>, SceneError> {
+        let scene = self.save_scene(|entry, chunk, slot| unsafe { (entry.to_bytes)(chunk, slot) })?;
+        bincode::serialize(&scene).map_err(SceneError::format)
+    }
+
+    /// Save all entities in the human-readable RON format.
+    pub fn save_ron(&self) -> Result<String, SceneError> {
+        let scene = self.save_scene(|entry, chunk, slot| unsafe { (entry.to_value)(chunk, slot) })?;
+        ron::ser::to_string_pretty(&scene, ron::ser::PrettyConfig::default()).map_err(SceneError::format)
+    }
+
+    /// Save all entities in the human-readable JSON format.
+    pub fn save_json(&self) -> Result<String, SceneError> {
+        let scene = self.save_scene(|entry, chunk, slot| unsafe { (entry.to_value)(chunk, slot) })?;
+        serde_json::to_string_pretty(&scene).map_err(SceneError::format)
+    }
+
+    /// Create the entities of a binary scene, returned in scene order.
+    ///
+    /// Entities created before an error occurred are kept.
+    pub fn load_binary(&mut self, data: &[u8]) -> Result<Vec<Entity>, SceneError> {
+        let scene = bincode::deserialize::<Scene<Vec<u8>>>(data).map_err(SceneError::format)?;
+        self.load_scene(scene, |entry, data| (entry.from_bytes)(&data))
+    }
+
+    /// Create the entities of a RON scene, see `load_binary`.
+    pub fn load_ron(&mut self, data: &str) -> Result<Vec<Entity>, SceneError> {
+        let scene = ron::de::from_str::<Scene<Value>>(data).map_err(SceneError::format)?;
+        self.load_scene(scene, |entry, data| (entry.from_value)(data))
+    }
+
+    /// Create the entities of a JSON scene, see `load_binary`.
+    pub fn load_json(&mut self, data: &str) -> Result<Vec<Entity>, SceneError> {
+        let scene = serde_json::from_str::<Scene<Value>>(data).map_err(SceneError::format)?;
+        self.load_scene(scene, |entry, data| (entry.from_value)(data))
+    }
+
+    fn save_scene<D, F>(&self, mut serialize: F) -> Result<Scene<D>, SceneError>
+    where
+        F: FnMut(&SerializeEntry, &ChunkPtr, SlotId) -> Result<D, SceneError>,
+    {
+        let groups = self.sorted_groups().collect::<Vec<_>>();
+
+        let mut entity_map = HashMap::new();
+        for group in &groups {
+            for (chunk, data) in group.comp_chunks[&ENTITY_COMP_ID].iter().zip(&group.chunk_data) {
+                for slot in 0..data.len {
+                    let entity = unsafe { *(chunk.ptr as *const Entity).add(slot) };
+                    let index = entity_map.len() as u32;
+                    entity_map.insert(entity.id, (entity.generation, index));
+                }
+            }
+        }
+        let _scope = EntityMapScope::new(EntityMap::Save(entity_map));
+
+        let mut names = HashMap::<ComponentId, u32>::new();
+        let mut components = Vec::new();
+        let mut entities = Vec::new();
+        for group in groups {
+            let mut entries = group
+                .components
+                .iter()
+                .filter_map(|id| self.serialize.get(id).map(|entry| (*id, entry)))
+                .collect::<Vec<_>>();
+            entries.sort_by_key(|(_, entry)| entry.name);
+
+            for (chunk, data) in group.chunk_data.iter().enumerate() {
+                for slot in 0..data.len {
+                    let mut entity = Vec::with_capacity(entries.len());
+                    for (id, entry) in &entries {
+                        let name = *names.entry(*id).or_insert_with(|| {
+                            components.push(entry.name.to_string());
+                            components.len() as u32 - 1
+                        });
+                        let data = serialize(*entry, &group.comp_chunks[id][chunk], slot)?;
+                        entity.push((name, data));
+                    }
+                    entities.push(entity);
+                }
+            }
+        }
+
+        Ok(Scene {
+            version: SCENE_VERSION,
+            components,
+            entities,
+        })
+    }
+
+    fn load_scene<D, F>(&mut self, scene: Scene<D>, mut deserialize: F) -> Result<Vec<Entity>, SceneError>
+    where
+        F: FnMut(&SerializeEntry, D) -> Result<Box<Any>, SceneError>,
+    {
+        if scene.version != SCENE_VERSION {
+            return Err(SceneError::Version(scene.version));
+        }
+
+        let entries = scene
+            .components
+            .iter()
+            .map(|name| {
+                self.serialize
+                    .values()
+                    .find(|entry| entry.name == *name)
+                    .cloned()
+                    .ok_or_else(|| SceneError::UnknownComponent(name.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Handles of all entities are required before loading components referencing them.
+        let num = scene.entities.len();
+        let ids = self.entities_free.reserve(num);
+        self.place_reserved_range(ids.end as usize);
+        let entities = ids
+            .clone()
+            .map(|id| Entity { id, generation: 0 })
+            .collect::<Vec<_>>();
+
+        let _scope = EntityMapScope::new(EntityMap::Load(entities.clone()));
+        for (entity, components) in entities.iter().zip(scene.entities) {
+            match load_components(&entries, components, &mut deserialize) {
+                Ok(components) => self.place_loaded(*entity, components),
+                Err(err) => {
+                    // Free the reserved ids of the entities which weren't created.
+                    self.entities_free.reserver().release(entity.id..ids.end);
+                    self.place_reserved_range(ids.end as usize);
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(entities)
+    }
+
+    /// Place a reserved entity directly into the group of its loaded components.
+    fn place_loaded(&mut self, entity: Entity, components: Vec<(SerializeEntry, Box<Any>)>) {
+        let mut comp_ids = components.iter().map(|(entry, _)| entry.id).collect::<Vec<_>>();
+        comp_ids.push(ENTITY_COMP_ID);
+        let group_id = self.define_group_components(comp_ids, Vec::new());
+
+        let (chunk, slots) = self.alloc_group_slots(group_id, 1, &[]);
+        let comp_chunks = &self.group_storages[&group_id].comp_chunks;
+        unsafe {
+            ptr::write((comp_chunks[&ENTITY_COMP_ID][chunk].ptr as *mut Entity).add(slots.start), entity);
+            for (entry, component) in components {
+                (entry.write)(component, &comp_chunks[&entry.id][chunk], slots.start);
+            }
+        }
+        self.entities[entity.id as usize] = EntityData {
+            generation: entity.generation,
+            group: group_id,
+            chunk,
+            slot: slots.start,
+        };
+        self.mark_added(group_id, chunk);
+    }
+}
+
+/// Deserialize the components of a single entity.
+fn load_components<D, F>(
+    entries: &[SerializeEntry],
+    components: Vec<(u32, D)>,
+    deserialize: &mut F,
+) -> Result<Vec<(SerializeEntry, Box<Any>)>, SceneError>
+where
+    F: FnMut(&SerializeEntry, D) -> Result<Box<Any>, SceneError>,
+{
+    let mut loaded = Vec::<(SerializeEntry, Box<Any>)>::with_capacity(components.len());
+    for (name, data) in components {
+        let entry = entries
+            .get(name as usize)
+            .ok_or_else(|| SceneError::Format("component index out of range".into()))?;
+        if loaded.iter().any(|(loaded, _)| loaded.id == entry.id) {
+            return Err(SceneError::Format("duplicate component".into()));
+        }
+        loaded.push((*entry, deserialize(entry, data)?));
+    }
+    Ok(loaded)
+}
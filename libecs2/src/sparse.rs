@@ -0,0 +1,156 @@
+//! Sparse set storage for components with `StorageHint::Sparse`.
+//!
+//! Sparse components aren't part of the group layout, adding or removing them
+//! doesn't move the entity between groups. Queries visit all groups for sparse
+//! components and check each slot individually.
+//!
+//! ```ignore
+//! #[derive(Component)]
+//! #[component(crate = "::tanya::ecs2", storage = "sparse")]
+//! struct Selected;
+//!
+//! world.add_component(entity, Selected);
+//! for pos in world.query_filtered::<&mut Pos, With<Selected>>() {
+//!     // ..
+//! }
+//! ```
+
+use std::any::{Any, TypeId};
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::ptr;
+
+use crate::{Component, ComponentId, Entity, EntityId, StorageHint};
+
+// Dense index of entities without the component.
+const INVALID_INDEX: u32 = u32::max_value();
+
+/// Components of a single type indexed by entity id.
+pub struct SparseSet<C> {
+    // Dense index of each entity id.
+    sparse: Vec<u32>,
+    entities: Vec<EntityId>,
+    values: Vec<UnsafeCell<C>>,
+}
+
+// Access to the values is synchronized by the world or the job system.
+unsafe impl<C: Send> Send for SparseSet<C> {}
+unsafe impl<C: Sync> Sync for SparseSet<C> {}
+
+impl<C> SparseSet<C> {
+    pub fn new() -> Self {
+        SparseSet {
+            sparse: Vec::new(),
+            entities: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    fn index(&self, entity: EntityId) -> Option<usize> {
+        match self.sparse.get(entity as usize) {
+            Some(index) if *index != INVALID_INDEX => Some(*index as usize),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn contains(&self, entity: EntityId) -> bool {
+        self.index(entity).is_some()
+    }
+
+    /// Pointer to the component of an entity.
+    ///
+    /// The caller has to ensure that mutable accesses aren't aliased.
+    pub(crate) fn get_ptr(&self, entity: EntityId) -> Option<*mut C> {
+        self.index(entity).map(|index| self.values[index].get())
+    }
+
+    /// Insert or replace the component of an entity, returning the previous value.
+    pub(crate) fn insert(&mut self, entity: EntityId, value: C) -> Option<C> {
+        if let Some(index) = self.index(entity) {
+            return Some(std::mem::replace(unsafe { &mut *self.values[index].get() }, value));
+        }
+
+        if self.sparse.len() <= entity as usize {
+            self.sparse.resize(entity as usize + 1, INVALID_INDEX);
+        }
+        self.sparse[entity as usize] = self.values.len() as u32;
+        self.entities.push(entity);
+        self.values.push(UnsafeCell::new(value));
+        None
+    }
+
+    /// Remove the component of an entity by moving the last component into its place.
+    pub(crate) fn remove(&mut self, entity: EntityId) -> Option<C> {
+        let index = self.index(entity)?;
+        self.sparse[entity as usize] = INVALID_INDEX;
+        self.entities.swap_remove(index);
+        let value = self.values.swap_remove(index).into_inner();
+        if let Some(moved) = self.entities.get(index) {
+            self.sparse[*moved as usize] = index as u32;
+        }
+        Some(value)
+    }
+}
+
+/// Type erased sparse set.
+pub(crate) trait SparseStorage: Send + Sync {
+    /// Drop the component of a freed entity, if any.
+    fn free(&mut self, entity: EntityId);
+    fn as_any(&self) -> &Any;
+    fn as_any_mut(&mut self) -> &mut Any;
+}
+
+impl<C: Send + Sync + 'static> SparseStorage for SparseSet<C> {
+    fn free(&mut self, entity: EntityId) {
+        self.remove(entity);
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Sparse sets of a world, receiving the sparse columns of created entities.
+pub struct SparseSets<'a> {
+    comp_map: &'a HashMap<TypeId, ComponentId>,
+    sets: &'a mut HashMap<ComponentId, Box<SparseStorage>>,
+}
+
+impl<'a> SparseSets<'a> {
+    pub(crate) fn new(
+        comp_map: &'a HashMap<TypeId, ComponentId>,
+        sets: &'a mut HashMap<ComponentId, Box<SparseStorage>>,
+    ) -> Self {
+        SparseSets { comp_map, sets }
+    }
+
+    /// Move one component per entity, starting at `values`, into the sparse set of `C`.
+    ///
+    /// Dense components are skipped, they are moved into the group chunks by `fill_slots`.
+    pub unsafe fn insert<C: Component>(&mut self, entities: &[Entity], values: *const C) {
+        if C::STORAGE != StorageHint::Sparse {
+            return;
+        }
+
+        let set = self
+            .sets
+            .get_mut(&self.comp_map[&TypeId::of::<C>()])
+            .and_then(|set| set.as_any_mut().downcast_mut::<SparseSet<C>>())
+            .unwrap();
+        for (i, entity) in entities.iter().enumerate() {
+            set.insert(entity.id, ptr::read(values.add(i)));
+        }
+    }
+}
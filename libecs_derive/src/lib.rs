@@ -1,20 +1,475 @@
+#![recursion_limit = "256"]
+
 #[macro_use]
 extern crate quote;
 extern crate proc_macro;
 
 use self::proc_macro::TokenStream;
-use syn::{parse_macro_input, DeriveInput};
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use syn::ext::IdentExt;
+use syn::parse::{Parse, ParseStream};
+use syn::{
+    parenthesized, parse_macro_input, Attribute, Data, DeriveInput, Fields, Ident, Index, LitStr,
+    Member, Token, Type,
+};
+
+/// Option of a derive attribute, e.g. `crate = "::tanya::ecs2"` or `default`.
+struct AttrArg {
+    name: Ident,
+    value: Option<LitStr>,
+}
+
+impl Parse for AttrArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        // `crate` is a keyword.
+        let name = input.call(Ident::parse_any)?;
+        let value = if input.peek(Token![=]) {
+            input.parse::<Token![=]>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        Ok(AttrArg { name, value })
+    }
+}
+
+struct AttrArgs(Vec<AttrArg>);
+
+impl Parse for AttrArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let content;
+        parenthesized!(content in input);
+        let args = content.parse_terminated::<_, Token![,]>(AttrArg::parse)?;
+        Ok(AttrArgs(args.into_iter().collect()))
+    }
+}
 
-#[proc_macro_derive(Component)]
+/// Collect the options of all `#[<attr>(..)]` attributes.
+fn parse_attrs(attrs: &[Attribute], attr: &str) -> syn::Result<Vec<AttrArg>> {
+    let mut args = Vec::new();
+    for attribute in attrs {
+        let segments = &attribute.path.segments;
+        if segments.len() == 1 && segments[0].ident == attr {
+            let AttrArgs(attr_args) = syn::parse2(attribute.tts.clone())?;
+            args.extend(attr_args);
+        }
+    }
+    Ok(args)
+}
+
+fn arg_value(arg: &AttrArg) -> syn::Result<&LitStr> {
+    arg.value
+        .as_ref()
+        .ok_or_else(|| syn::Error::new(arg.name.span(), format!("`{}` requires a value", arg.name)))
+}
+
+fn parse_crate_path(lit: &LitStr) -> syn::Result<TokenStream2> {
+    lit.value()
+        .parse()
+        .map_err(|_| syn::Error::new(lit.span(), "invalid crate path"))
+}
+
+/// Implement `Component` for a type.
+///
+/// Options of the `#[component(..)]` attribute:
+///
+/// * `crate = "path"`: path of the ECS crate, defaults to `::tanya::ecs`.
+///   Use `::tanya::ecs2` for `tanya-ecs2` or `crate` inside the ECS crates.
+/// * `name = "name"`: stable type name, defaults to the module path and type name.
+///   Required for generic types, as the default doesn't include the type parameters.
+/// * `storage = "dense" | "sparse"`: preferred storage layout. `tanya-ecs` only
+///   supports dense storage.
+/// * `default`: component can be default-constructed using `Default`.
+#[proc_macro_derive(Component, attributes(component))]
 pub fn component(input: TokenStream) -> TokenStream {
     let ast = parse_macro_input!(input as DeriveInput);
+    match derive_component(&ast) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn derive_component(ast: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+    let mut krate = quote! { ::tanya::ecs };
+    let mut type_name = None;
+    let mut storage = None;
+    let mut default = None;
+    for arg in parse_attrs(&ast.attrs, "component")? {
+        match &*arg.name.to_string() {
+            "crate" => krate = parse_crate_path(arg_value(&arg)?)?,
+            "name" => {
+                let value = arg_value(&arg)?;
+                type_name = Some(quote! { #value });
+            }
+            "storage" => {
+                let value = arg_value(&arg)?;
+                let hint = match &*value.value() {
+                    "dense" => Ident::new("Dense", Span::call_site()),
+                    // Unknown variant for `tanya-ecs` paths other than the default.
+                    "sparse" => Ident::new("Sparse", value.span()),
+                    _ => {
+                        return Err(syn::Error::new(
+                            value.span(),
+                            "expected `dense` or `sparse`",
+                        ))
+                    }
+                };
+                storage = Some(hint);
+            }
+            "default" => default = Some(()),
+            _ => return Err(syn::Error::new(arg.name.span(), "unknown component option")),
+        }
+    }
+
+    let generic = ast.generics.type_params().next().is_some();
+    let type_name = match type_name {
+        Some(type_name) => type_name,
+        None if generic => {
+            return Err(syn::Error::new(
+                name.span(),
+                "generic components require a type name, e.g. `#[component(name = \"..\")]`",
+            ))
+        }
+        None => quote! { concat!(module_path!(), "::", stringify!(#name)) },
+    };
+
+    let sparse = storage.as_ref().map_or(false, |hint| hint == "Sparse");
+    if sparse && krate.to_string() == quote!(::tanya::ecs).to_string() {
+        return Err(syn::Error::new(
+            name.span(),
+            "`tanya-ecs` has no sparse storage, use `tanya-ecs2` with `#[component(crate = \"::tanya::ecs2\")]`",
+        ));
+    }
+
+    let storage = storage.map(|hint| {
+        quote! {
+            const STORAGE: #krate::StorageHint = #krate::StorageHint::#hint;
+        }
+    });
+    let default = default.map(|_| {
+        quote! {
+            fn default_value() -> Option<Self> {
+                Some(::std::default::Default::default())
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics #krate::Component for #name #ty_generics #where_clause {
+            #storage
+
+            fn type_name() -> &'static str {
+                #type_name
+            }
+
+            #default
+        }
+    })
+}
+
+/// Implement `IComponentGroup` of `tanya-ecs2` for a struct with named component fields.
+///
+/// Generates the build stream `<Name>Stream` with a `Vec` per field and the
+/// query item `<Name>Item<'a>` referencing the components of an entity.
+///
+/// The crate path defaults to `::tanya::ecs2` and can be changed with
+/// `#[group(crate = "path")]`.
+#[proc_macro_derive(ComponentGroup, attributes(group))]
+pub fn component_group(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as DeriveInput);
+    match derive_component_group(&ast) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn derive_component_group(ast: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &ast.ident;
+    let vis = &ast.vis;
+    if !ast.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            name.span(),
+            "ComponentGroup can't be derived for generic structs",
+        ));
+    }
+
+    let mut krate = quote! { ::tanya::ecs2 };
+    for arg in parse_attrs(&ast.attrs, "group")? {
+        match &*arg.name.to_string() {
+            "crate" => krate = parse_crate_path(arg_value(&arg)?)?,
+            _ => return Err(syn::Error::new(arg.name.span(), "unknown group option")),
+        }
+    }
+
+    let fields = match ast.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => fields.named.iter().collect::<Vec<_>>(),
+            _ => {
+                return Err(syn::Error::new(
+                    name.span(),
+                    "ComponentGroup requires named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new(
+                name.span(),
+                "ComponentGroup can only be derived for structs",
+            ))
+        }
+    };
+
+    let stream = Ident::new(&format!("{}Stream", name), name.span());
+    let item = Ident::new(&format!("{}Item", name), name.span());
+
+    let names = &fields
+        .iter()
+        .map(|f| f.ident.as_ref().unwrap())
+        .collect::<Vec<_>>()[..];
+    let tys = &fields.iter().map(|f| &f.ty).collect::<Vec<_>>()[..];
+    let indices = &(0..fields.len()).collect::<Vec<_>>()[..];
+    let vis_fields = &fields.iter().map(|f| &f.vis).collect::<Vec<_>>()[..];
+
+    // `quote` can't repeat the same variable twice within a repetition.
+    let push_fields = names.iter().map(|name| quote! { stream.#name.push(group.#name); });
+    let release_fields = names.iter().map(|name| {
+        quote! {
+            assert_eq!(stream.#name.len(), num);
+            stream.#name.set_len(0);
+        }
+    });
+    let fill_fields = names.iter().zip(tys).map(|(name, ty)| {
+        quote! {
+            if <#ty as #krate::Component>::STORAGE == #krate::StorageHint::Dense {
+                ::std::ptr::copy_nonoverlapping(
+                    stream.#name.as_ptr().add(stream_base),
+                    (comp_chunks[&comp_map[&::std::any::TypeId::of::<#ty>()]][chunk].ptr as *mut #ty).add(slot_base),
+                    num,
+                );
+            }
+        }
+    });
+    let sparse_fields = names.iter().zip(tys).map(|(name, ty)| {
+        quote! { sparse.insert::<#ty>(entities, stream.#name.as_ptr().add(stream_base)); }
+    });
+
+    Ok(quote! {
+        #vis struct #stream {
+            #(#vis_fields #names: Vec<#tys>,)*
+        }
+
+        #vis struct #item<'a> {
+            #(#vis_fields #names: &'a #tys,)*
+        }
+
+        impl<'a> #krate::IComponentGroup<'a> for #name {
+            type BuildStream = #stream;
+            type Iterator = #krate::GroupIterator<'a, Self>;
+            type Item = #item<'a>;
+
+            fn iter(
+                comp_map: &::std::collections::HashMap<::std::any::TypeId, #krate::ComponentId>,
+                group: &'a #krate::GroupStorage,
+            ) -> Self::Iterator {
+                #krate::GroupIterator::new(comp_map, group, &[#(::std::any::TypeId::of::<#tys>()),*])
+            }
+
+            fn define_components(
+                comp_map: &::std::collections::HashMap<::std::any::TypeId, #krate::ComponentId>,
+            ) -> Vec<#krate::ComponentId> {
+                vec![
+                    comp_map[&::std::any::TypeId::of::<#krate::Entity>()],
+                    #(comp_map[&::std::any::TypeId::of::<#tys>()]),*
+                ]
+            }
+
+            fn into_stream<Groups: IntoIterator<Item = Self>>(groups: Groups) -> Self::BuildStream {
+                let mut stream = #stream {
+                    #(#names: Vec::new(),)*
+                };
+                for group in groups {
+                    #(#push_fields)*
+                }
+                stream
+            }
+
+            unsafe fn release_stream(stream: &mut Self::BuildStream, num: usize) {
+                #(#release_fields)*
+            }
+
+            unsafe fn fill_slots(
+                comp_map: &::std::collections::HashMap<::std::any::TypeId, #krate::ComponentId>,
+                comp_chunks: &mut ::std::collections::HashMap<#krate::ComponentId, Vec<#krate::ChunkPtr>>,
+                chunk: #krate::ChunkId,
+                slot_base: #krate::SlotId,
+                stream: &Self::BuildStream,
+                stream_base: usize,
+                num: usize,
+            ) {
+                #(#fill_fields)*
+            }
+
+            unsafe fn fill_sparse(
+                sparse: &mut #krate::SparseSets,
+                entities: &[#krate::Entity],
+                stream: &Self::BuildStream,
+                stream_base: usize,
+            ) {
+                #(#sparse_fields)*
+            }
+
+            unsafe fn fetch(
+                chunks: &[&'a [#krate::ChunkPtr]],
+                chunk: #krate::ChunkId,
+                slot: #krate::SlotId,
+            ) -> Self::Item {
+                #item {
+                    #(#names: &*(chunks[#indices][chunk].ptr as *const #tys).add(slot),)*
+                }
+            }
+        }
+    })
+}
+
+/// Type name as spelled in the source, e.g. `[f32; 2]` instead of the token
+/// spacing `[ f32 ; 2 ]` of the token stream.
+///
+/// Only the whitespace is restored, paths are kept as written since the derive
+/// can't resolve them.
+fn type_name(ty: &Type) -> String {
+    let is_word = |c: Option<char>| c.map_or(false, |c| c.is_alphanumeric() || c == '_' || c == '\'');
+
+    let tokens = quote!(#ty).to_string();
+    let mut name = String::new();
+    for token in tokens.split_whitespace() {
+        let space = (is_word(name.chars().last()) && is_word(token.chars().next()))
+            || name.ends_with(',')
+            || name.ends_with(';')
+            || name.ends_with('+')
+            || name.ends_with("->")
+            || token == "+"
+            || token == "->";
+        if space && !name.is_empty() {
+            name.push(' ');
+        }
+        name.push_str(token);
+    }
+    name
+}
+
+/// Implement `Reflect` of `tanya-ecs2` for a struct.
+///
+/// All fields have to implement `Reflect`, fields marked with `#[reflect(skip)]`
+/// are hidden. Field type names are spelled as in the struct definition, see
+/// `FieldInfo::type_name`. The crate path defaults to `::tanya::ecs2` and can
+/// be changed with `#[reflect(crate = "path")]` on the struct.
+#[proc_macro_derive(Reflect, attributes(reflect))]
+pub fn reflect(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as DeriveInput);
+    match derive_reflect(&ast) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
 
+fn derive_reflect(ast: &DeriveInput) -> syn::Result<TokenStream2> {
     let name = &ast.ident;
     let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
 
-    let tokens = quote! {
-        impl #impl_generics ::tanya::ecs::Component for #name #ty_generics #where_clause { }
+    let mut krate = quote! { ::tanya::ecs2 };
+    for arg in parse_attrs(&ast.attrs, "reflect")? {
+        match &*arg.name.to_string() {
+            "crate" => krate = parse_crate_path(arg_value(&arg)?)?,
+            _ => return Err(syn::Error::new(arg.name.span(), "unknown reflect option")),
+        }
+    }
+
+    let fields = match ast.data {
+        Data::Struct(ref data) => &data.fields,
+        _ => {
+            return Err(syn::Error::new(
+                name.span(),
+                "Reflect can only be derived for structs",
+            ))
+        }
     };
 
-    tokens.into()
+    let mut members = Vec::new();
+    let mut field_names = Vec::new();
+    let mut field_infos = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let mut skip = false;
+        for arg in parse_attrs(&field.attrs, "reflect")? {
+            match &*arg.name.to_string() {
+                "skip" => skip = true,
+                _ => {
+                    return Err(syn::Error::new(
+                        arg.name.span(),
+                        "unknown reflect field option",
+                    ))
+                }
+            }
+        }
+        if skip {
+            continue;
+        }
+
+        let member = match field.ident {
+            Some(ref ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(Index::from(i)),
+        };
+        let field_name = match field.ident {
+            Some(ref ident) => ident.to_string(),
+            None => i.to_string(),
+        };
+        let field_name = LitStr::new(&field_name, Span::call_site());
+        let ty = &field.ty;
+        let type_name = LitStr::new(&type_name(ty), Span::call_site());
+
+        field_infos.push(quote! {
+            #krate::FieldInfo { name: #field_name, type_name: #type_name }
+        });
+        members.push(member);
+        field_names.push(field_name);
+    }
+
+    let members = &members[..];
+    let field_names = &field_names[..];
+    let field_infos = &field_infos[..];
+
+    Ok(quote! {
+        impl #impl_generics #krate::Reflect for #name #ty_generics #where_clause {
+            fn fields(&self) -> &'static [#krate::FieldInfo] {
+                const FIELDS: &[#krate::FieldInfo] = &[
+                    #(#field_infos,)*
+                ];
+                FIELDS
+            }
+
+            fn field(&self, name: &str) -> Option<&#krate::Reflect> {
+                match name {
+                    #(#field_names => Some(&self.#members),)*
+                    _ => None,
+                }
+            }
+
+            fn field_mut(&mut self, name: &str) -> Option<&mut #krate::Reflect> {
+                match name {
+                    #(#field_names => Some(&mut self.#members),)*
+                    _ => None,
+                }
+            }
+
+            fn as_any(&self) -> &::std::any::Any {
+                self
+            }
+
+            fn as_any_mut(&mut self) -> &mut ::std::any::Any {
+                self
+            }
+        }
+    })
 }
@@ -0,0 +1,421 @@
+//! Work-stealing executor for futures.
+//!
+//! Every worker thread owns a local FIFO deque. Tasks spawned or woken from
+//! inside a worker go to its local deque, everything else goes through the
+//! global injector queue. Idle workers first drain the injector and then try
+//! to steal from the other workers before going to sleep.
+//!
+//! A panicking task is dropped without taking down its worker. Dropping the
+//! task drops its pending `notify::Sender`s, which propagates the panic to all
+//! tasks awaiting the corresponding receivers.
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use futures::future::FutureObj;
+use futures::task::{self, Poll, Spawn, SpawnError, Wake};
+use std::cell::RefCell;
+use std::future::Future;
+use std::io;
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+
+// Task states, see `Task::wake` and `Task::run`.
+const IDLE: usize = 0;
+const SCHEDULED: usize = 1;
+const RUNNING: usize = 2;
+const NOTIFIED: usize = 3;
+const COMPLETE: usize = 4;
+
+thread_local! {
+    static WORKER: RefCell<Option<WorkerContext>> = RefCell::new(None);
+}
+
+struct WorkerContext {
+    shared: *const Shared,
+    local: Worker<Arc<Task>>,
+}
+
+struct Sleep {
+    lock: Mutex<()>,
+    cvar: Condvar,
+    sleeping: AtomicUsize,
+}
+
+struct Shared {
+    injector: Injector<Arc<Task>>,
+    stealers: Vec<Stealer<Arc<Task>>>,
+    sleep: Sleep,
+    shutdown: AtomicBool,
+}
+
+impl Shared {
+    fn schedule(self: &Arc<Self>, task: Arc<Task>) {
+        let task = WORKER.with(|worker| match *worker.borrow() {
+            Some(ref ctxt) if ctxt.shared == &**self as *const _ => {
+                ctxt.local.push(task);
+                None
+            }
+            _ => Some(task),
+        });
+
+        if let Some(task) = task {
+            self.injector.push(task);
+        }
+
+        self.notify_one();
+    }
+
+    fn notify_one(&self) {
+        if self.sleep.sleeping.load(SeqCst) > 0 {
+            let _guard = self.sleep.lock.lock().unwrap();
+            self.sleep.cvar.notify_one();
+        }
+    }
+
+    fn notify_all(&self) {
+        let _guard = self.sleep.lock.lock().unwrap();
+        self.sleep.cvar.notify_all();
+    }
+
+    fn has_work(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|s| !s.is_empty())
+    }
+
+    fn find_task(&self, local: &Worker<Arc<Task>>) -> Option<Arc<Task>> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(|s| s.steal()).collect())
+            })
+            .find(|s| !s.is_retry())
+            .and_then(|s| s.success())
+        })
+    }
+
+    fn run_worker(self: Arc<Self>, local: Worker<Arc<Task>>) {
+        WORKER.with(|worker| {
+            *worker.borrow_mut() = Some(WorkerContext {
+                shared: &*self as *const _,
+                local,
+            });
+        });
+
+        loop {
+            let task = WORKER.with(|worker| {
+                let worker = worker.borrow();
+                let ctxt = worker.as_ref().unwrap();
+                self.find_task(&ctxt.local)
+            });
+
+            if let Some(task) = task {
+                task.run();
+                continue;
+            }
+
+            if self.shutdown.load(SeqCst) {
+                break;
+            }
+
+            // Re-check under the lock to not miss a wakeup from `schedule`.
+            let guard = self.sleep.lock.lock().unwrap();
+            self.sleep.sleeping.fetch_add(1, SeqCst);
+            if !self.has_work() && !self.shutdown.load(SeqCst) {
+                let _guard = self.sleep.cvar.wait(guard).unwrap();
+            }
+            self.sleep.sleeping.fetch_sub(1, SeqCst);
+        }
+
+        WORKER.with(|worker| *worker.borrow_mut() = None);
+    }
+}
+
+struct Task {
+    future: Mutex<Option<FutureObj<'static, ()>>>,
+    state: AtomicUsize,
+    shared: Weak<Shared>,
+}
+
+impl Task {
+    fn run(self: Arc<Self>) {
+        let waker = task::local_waker_from_nonlocal(self.clone());
+        let mut future = self.future.lock().unwrap();
+
+        loop {
+            self.state.store(RUNNING, SeqCst);
+
+            // Catch panics to keep the worker alive, the future is dropped below.
+            let poll = panic::catch_unwind(AssertUnwindSafe(|| match *future {
+                Some(ref mut f) => Pin::new(f).poll(&waker).is_ready(),
+                None => true,
+            }));
+            let done = poll.unwrap_or(true);
+
+            if done {
+                *future = None;
+                self.state.store(COMPLETE, SeqCst);
+                return;
+            }
+
+            match self.state.compare_and_swap(RUNNING, IDLE, SeqCst) {
+                RUNNING => return,
+                // Woken up while polling, poll again.
+                NOTIFIED => continue,
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+impl Wake for Task {
+    fn wake(arc_self: &Arc<Self>) {
+        loop {
+            match arc_self.state.load(SeqCst) {
+                IDLE => {
+                    if arc_self.state.compare_and_swap(IDLE, SCHEDULED, SeqCst) == IDLE {
+                        if let Some(shared) = arc_self.shared.upgrade() {
+                            shared.schedule(arc_self.clone());
+                        }
+                        return;
+                    }
+                }
+                RUNNING => {
+                    if arc_self.state.compare_and_swap(RUNNING, NOTIFIED, SeqCst) == RUNNING {
+                        return;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+pub struct ThreadPoolBuilder {
+    num_threads: usize,
+    thread_name: Option<Box<FnMut(usize) -> String>>,
+    stack_size: Option<usize>,
+}
+
+impl ThreadPoolBuilder {
+    pub fn new() -> Self {
+        ThreadPoolBuilder {
+            num_threads: 0,
+            thread_name: None,
+            stack_size: None,
+        }
+    }
+
+    /// Number of worker threads, defaults to the number of logical cpus.
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.num_threads = num_threads;
+        self
+    }
+
+    pub fn thread_name<F>(mut self, name: F) -> Self
+    where
+        F: FnMut(usize) -> String + 'static,
+    {
+        self.thread_name = Some(Box::new(name));
+        self
+    }
+
+    pub fn stack_size(mut self, stack_size: usize) -> Self {
+        self.stack_size = Some(stack_size);
+        self
+    }
+
+    pub fn build(mut self) -> io::Result<ThreadPool> {
+        let num_threads = if self.num_threads > 0 {
+            self.num_threads
+        } else {
+            num_cpus::get()
+        };
+
+        let locals = (0..num_threads)
+            .map(|_| Worker::new_fifo())
+            .collect::<Vec<_>>();
+
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: locals.iter().map(|local| local.stealer()).collect(),
+            sleep: Sleep {
+                lock: Mutex::new(()),
+                cvar: Condvar::new(),
+                sleeping: AtomicUsize::new(0),
+            },
+            shutdown: AtomicBool::new(false),
+        });
+
+        let mut pool = ThreadPool {
+            shared: shared.clone(),
+            threads: Vec::with_capacity(num_threads),
+        };
+
+        for (i, local) in locals.into_iter().enumerate() {
+            let mut builder = thread::Builder::new();
+            if let Some(ref mut name) = self.thread_name {
+                builder = builder.name(name(i));
+            }
+            if let Some(stack_size) = self.stack_size {
+                builder = builder.stack_size(stack_size);
+            }
+
+            let shared = shared.clone();
+            let thread = builder.spawn(move || shared.run_worker(local))?;
+            pool.threads.push(thread);
+        }
+
+        Ok(pool)
+    }
+}
+
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    pub fn num_threads(&self) -> usize {
+        self.threads.len()
+    }
+}
+
+impl Spawn for ThreadPool {
+    fn spawn_obj(&mut self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+        self.status()?;
+
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(future)),
+            state: AtomicUsize::new(SCHEDULED),
+            shared: Arc::downgrade(&self.shared),
+        });
+        self.shared.schedule(task);
+
+        Ok(())
+    }
+
+    fn status(&self) -> Result<(), SpawnError> {
+        if self.shared.shutdown.load(SeqCst) {
+            Err(SpawnError::shutdown())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, SeqCst);
+        self.shared.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::SpawnExt;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn spawn_many() {
+        let mut pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let (tx, rx) = mpsc::channel();
+        for i in 0..1000 {
+            let tx = tx.clone();
+            pool.spawn(async move { tx.send(i).unwrap(); }).unwrap();
+        }
+        drop(tx);
+
+        let mut values = rx.iter().collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(values, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn panicking_task() {
+        let mut pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let (sender, recv) = crate::notify::channel();
+        let (tx, rx) = mpsc::channel();
+
+        pool.spawn(async move {
+            let _sender = sender;
+            panic!("task panicked");
+        })
+        .unwrap();
+
+        // The panic propagates to awaiting tasks instead of blocking them forever.
+        let waiting = tx.clone();
+        pool.spawn(async move {
+            await!(recv);
+            waiting.send("unreachable").unwrap();
+        })
+        .unwrap();
+
+        // The worker survived both panics.
+        pool.spawn(async move { tx.send("done").unwrap(); }).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("done"));
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn join_all_after_panic() {
+        struct SendOnDrop(mpsc::Sender<&'static str>);
+        impl Drop for SendOnDrop {
+            fn drop(&mut self) {
+                self.0.send("joined").unwrap();
+            }
+        }
+
+        let mut pool = ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+        let (failing, failed) = crate::notify::channel();
+        let (slow, done) = crate::notify::channel();
+        let (tx, rx) = mpsc::channel();
+
+        pool.spawn(async move {
+            let _sender = failing;
+            panic!("task panicked");
+        })
+        .unwrap();
+        let slow_tx = tx.clone();
+        pool.spawn(async move {
+            thread::sleep(Duration::from_millis(50));
+            slow_tx.send("slow").unwrap();
+            slow.notify();
+        })
+        .unwrap();
+
+        // The panic is only raised after the remaining task completed.
+        pool.spawn(async move {
+            let _joined = SendOnDrop(tx);
+            await!(crate::notify::join_all(vec![failed, done]));
+        })
+        .unwrap();
+
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("slow"));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("joined"));
+    }
+
+    #[test]
+    fn wake_from_other_task() {
+        let mut pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let (sender, recv) = crate::notify::channel();
+        let (tx, rx) = mpsc::channel();
+
+        pool.spawn(async move {
+            await!(recv);
+            tx.send(()).unwrap();
+        })
+        .unwrap();
+        pool.spawn(async move { sender.notify(); }).unwrap();
+
+        rx.recv().unwrap();
+    }
+}
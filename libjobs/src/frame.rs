@@ -1,3 +1,4 @@
+use crate::io::{CancelGuard, IoFrame, IoService};
 use crate::jobs::{self, Job, Scope};
 use crate::resource::{self, Resource, ResourceTy};
 use crate::world::{World};
@@ -36,6 +37,14 @@ impl AccessPattern {
             (AccessPattern::RaW { reads, .. }, Access::Exclusive) => reads.iter().map(|j| recvs[*j].recv.clone()).collect::<Vec<_>>(),
         }
     }
+
+    fn collect_all_jobs(&self, recvs: &[Job]) -> Vec<notify::Receiver> {
+        match self {
+            AccessPattern::Read(jobs) => jobs.iter().map(|j| recvs[*j].recv.clone()).collect::<Vec<_>>(),
+            AccessPattern::Write(job) => vec![recvs[*job].recv.clone()],
+            AccessPattern::RaW { write, reads } => reads.iter().chain(Some(write)).map(|j| recvs[*j].recv.clone()).collect::<Vec<_>>(),
+        }
+    }
 }
 
 type JobId = usize;
@@ -73,6 +82,9 @@ pub struct FrameBuilder {
     state: RefCell<State>,
     pool: jobs::Pool,
     access_history: HashMap<ResourceId, AccessPattern>,
+    barrier: Option<notify::Receiver>,
+    barrier_start: JobId,
+    io: Vec<CancelGuard>,
 }
 
 impl FrameBuilder {
@@ -85,9 +97,30 @@ impl FrameBuilder {
             }),
             pool: scope.pool.clone(),
             access_history: HashMap::new(),
+            barrier: None,
+            barrier_start: 0,
+            io: Vec::new(),
+        }
+    }
+
+    /// Spawner for tasks outside of the frame dependency tracking, e.g. for
+    /// splitting up a job into smaller tasks.
+    pub fn scope(&self) -> Scope {
+        Scope {
+            pool: self.pool.clone(),
         }
     }
 
+    /// Handle for issuing reads from jobs of this frame.
+    ///
+    /// Outstanding reads are cancelled when the dispatched frame completes or is
+    /// dropped, or when the builder is dropped without dispatching.
+    pub fn io(&mut self, service: &IoService) -> IoFrame {
+        let io = service.frame();
+        self.io.push(io.cancel_on_drop());
+        io
+    }
+
     pub fn query<R: Resource>(&self, world_id: usize) -> ResourceHandle<R> {
         let key = ResourceTy::new::<R>();
         ResourceHandle {
@@ -103,6 +136,7 @@ impl FrameBuilder {
         let state = &mut self.state.borrow_mut();
         let access = mem::replace(&mut state.access, AccessMap::new());
         let access_history = &mut self.access_history;
+        let barrier = self.barrier.clone();
         let jobs = &mut state.jobs;
         let (sender, recv) = notify::channel();
         let job = Job {
@@ -152,6 +186,7 @@ impl FrameBuilder {
                     }
                 })
                 .flatten()
+                .chain(barrier)
                 .collect::<Vec<_>>();
 
             async move {
@@ -172,6 +207,61 @@ impl FrameBuilder {
         recv
     }
 
+    /// Insert a barrier waiting for all previously spawned jobs.
+    ///
+    /// All jobs spawned afterwards depend on the barrier.
+    pub fn barrier(&mut self) -> notify::Receiver {
+        let recvs = self.state.borrow().jobs[self.barrier_start..]
+            .iter()
+            .map(|job| job.recv.clone())
+            .collect::<Vec<_>>();
+
+        // Everything recorded so far completes before the barrier.
+        self.access_history.clear();
+        self.barrier_start = self.state.borrow().jobs.len();
+        self.spawn_barrier(recvs)
+    }
+
+    /// Insert a barrier waiting for all previously spawned jobs accessing any of the given resources.
+    ///
+    /// All jobs spawned afterwards depend on the barrier.
+    pub fn barrier_on(&mut self, resources: &[ResourceId]) -> notify::Receiver {
+        let recvs = {
+            let state = self.state.borrow();
+            let jobs = &state.jobs;
+            let access_history = &mut self.access_history;
+            resources
+                .iter()
+                .filter_map(|id| access_history.remove(id))
+                .flat_map(|pattern| pattern.collect_all_jobs(jobs))
+                .chain(self.barrier.clone())
+                .collect::<Vec<_>>()
+        };
+
+        self.spawn_barrier(recvs)
+    }
+
+    fn spawn_barrier(&mut self, recvs: Vec<notify::Receiver>) -> notify::Receiver {
+        let (sender, recv) = notify::channel();
+        self.state.borrow_mut().jobs.push(Job {
+            recv: recv.clone(),
+        });
+
+        let barrier = async move {
+            for recv in recvs {
+                await!(recv);
+            }
+            sender.notify();
+        };
+        {
+            let mut pool = self.pool.lock().unwrap();
+            SpawnExt::spawn(&mut pool.0, barrier).unwrap();
+        }
+
+        self.barrier = Some(recv.clone());
+        recv
+    }
+
     pub fn access(&self, world: &mut World) -> WorldHandle {
         let worlds = &mut self.state.borrow_mut().worlds;
         let id = worlds.len();
@@ -192,10 +282,13 @@ impl FrameBuilder {
             f = Frame::new(Box::new(result.map(|_| ())));
         }
 
-        f
+        // IO handles are cancelled together with the frame.
+        let io = self.io;
+        Frame::new(Box::new(f.map(move |_| drop(io))))
     }
 
-    fn access_resource(&self, id: ResourceId, access: Access) {
+    /// Register access to a resource for the next spawned job.
+    pub fn access_resource(&self, id: ResourceId, access: Access) {
         self.state.borrow_mut().access.add(id, access);
     }
 }
@@ -238,4 +331,123 @@ impl<R> ResourceHandle<R> {
     pub fn id(&self) -> ResourceId {
         self.id
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::{JobSystem, ThreadPoolBuilder};
+    use futures::executor::block_on;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    type Log = Arc<Mutex<Vec<&'static str>>>;
+
+    fn run<F>(build: F)
+    where
+        F: FnOnce(&mut FrameBuilder, &mut World) + Send,
+    {
+        let mut jobs = JobSystem::new(ThreadPoolBuilder::new().num_threads(4).build().unwrap());
+        let mut world = World::new();
+        world.add_resource(0u32);
+        jobs.scope(|scope| {
+            let mut frame = FrameBuilder::new(&scope);
+            build(&mut frame, &mut world);
+            block_on(frame.dispatch());
+        });
+    }
+
+    // Receiver notified by another thread after `ms` milliseconds, logging `name`.
+    fn gate(log: &Log, ms: u64, name: &'static str) -> notify::Receiver {
+        let (sender, recv) = notify::channel();
+        let log = log.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(ms));
+            log.lock().unwrap().push(name);
+            sender.notify();
+        });
+        recv
+    }
+
+    fn log_job(frame: &mut FrameBuilder, log: &Log, name: &'static str, gate: Option<notify::Receiver>) {
+        let log = log.clone();
+        let _ = frame.spawn_job(async move {
+            if let Some(gate) = gate {
+                await!(gate);
+            }
+            log.lock().unwrap().push(name);
+        });
+    }
+
+    #[test]
+    fn barrier() {
+        let log = Log::default();
+        run(|frame, _| {
+            log_job(frame, &log, "a", Some(gate(&log, 100, "open")));
+            log_job(frame, &log, "b", None);
+            let _ = frame.barrier();
+            log_job(frame, &log, "c", None);
+        });
+        assert_eq!(*log.lock().unwrap(), vec!["b", "open", "a", "c"]);
+    }
+
+    #[test]
+    fn barrier_on() {
+        let log = Log::default();
+        run(|frame, world| {
+            let counter = frame.access(world).query::<u32>();
+            // Doesn't access the counter and isn't waited for by the barrier.
+            log_job(frame, &log, "unrelated", Some(gate(&log, 200, "open unrelated")));
+
+            let mut value = counter.read_write(frame);
+            let write = gate(&log, 50, "open write");
+            let write_log = log.clone();
+            let _ = frame.spawn_job(async move {
+                await!(write);
+                *value += 1;
+                write_log.lock().unwrap().push("write");
+            });
+
+            let _ = frame.barrier_on(&[counter.id()]);
+            log_job(frame, &log, "after", None);
+        });
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["open write", "write", "after", "open unrelated", "unrelated"]
+        );
+    }
+
+    #[test]
+    fn jobs_depend_on_barrier() {
+        let log = Log::default();
+        run(|frame, world| {
+            let counter = frame.access(world).query::<u32>();
+            let mut value = counter.read_write(frame);
+            let write = gate(&log, 100, "open");
+            let write_log = log.clone();
+            let _ = frame.spawn_job(async move {
+                await!(write);
+                *value = 1;
+                write_log.lock().unwrap().push("write");
+            });
+
+            // The barrier resets the access history, later accesses only wait for the barrier.
+            let _ = frame.barrier();
+            let value = counter.read(frame);
+            let read_log = log.clone();
+            let _ = frame.spawn_job(async move {
+                assert_eq!(*value, 1);
+                read_log.lock().unwrap().push("read");
+            });
+
+            // Resource barriers chain onto the previous barrier.
+            let _ = frame.barrier_on(&[]);
+            log_job(frame, &log, "after", None);
+        });
+
+        let mut log = log.lock().unwrap().clone();
+        assert_eq!(log[..2], ["open", "write"]);
+        log[2..].sort();
+        assert_eq!(log[2..], ["after", "read"]);
+    }
+}
@@ -0,0 +1,327 @@
+//! Hot-reloadable resources backed by files.
+//!
+//! A `Watcher` polls the modification time of all watched files in a background
+//! job (see `Watcher::watch`) and deserializes changed files into a pending slot
+//! of the corresponding `HotResource`. The new value is swapped in by a job with exclusive access to
+//! the resource (see `reload` and `HotReload`), so running jobs never observe
+//! a partially updated value.
+
+use crate::frame::{FrameBuilder, WorldHandle};
+use crate::notify;
+use crate::resource::{Resource, Write};
+use crate::system::System;
+use futures::task::SpawnExt;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Converts file contents into a resource value.
+pub trait Deserializer<T>: Send + 'static {
+    fn deserialize(&self, data: &[u8]) -> io::Result<T>;
+}
+
+impl<T, F> Deserializer<T> for F
+where
+    F: Fn(&[u8]) -> io::Result<T> + Send + 'static,
+{
+    fn deserialize(&self, data: &[u8]) -> io::Result<T> {
+        self(data)
+    }
+}
+
+type Pending<T> = Arc<Mutex<Option<io::Result<T>>>>;
+
+pub struct HotResource<T> {
+    value: T,
+    path: PathBuf,
+    pending: Pending<T>,
+    error: Option<io::Error>,
+}
+
+impl<T> HotResource<T> {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Error of the last failed reload attempt, the previous value is kept in this case.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    /// Swap in a reloaded value if available.
+    ///
+    /// Returns `true` if the value has been replaced.
+    pub fn apply(&mut self) -> bool {
+        let pending = self.pending.lock().unwrap().take();
+        match pending {
+            Some(Ok(value)) => {
+                self.value = value;
+                self.error = None;
+                true
+            }
+            Some(Err(err)) => {
+                self.error = Some(err);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+impl<T> Deref for HotResource<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+trait Watch: Send {
+    /// Whether the corresponding `HotResource` is still alive.
+    fn is_alive(&self) -> bool;
+    fn poll(&mut self);
+}
+
+struct WatchEntry<T, D> {
+    path: PathBuf,
+    stamp: Option<(SystemTime, u64)>,
+    deserializer: D,
+    pending: Pending<T>,
+}
+
+impl<T, D> Watch for WatchEntry<T, D>
+where
+    T: Send,
+    D: Deserializer<T>,
+{
+    fn is_alive(&self) -> bool {
+        Arc::strong_count(&self.pending) > 1
+    }
+
+    fn poll(&mut self) {
+        let stamp = match fs::metadata(&self.path).and_then(|m| Ok((m.modified()?, m.len()))) {
+            Ok(stamp) => Some(stamp),
+            // File might be temporarily missing while being saved.
+            Err(_) => return,
+        };
+
+        if stamp == self.stamp {
+            return;
+        }
+        self.stamp = stamp;
+
+        let value = fs::read(&self.path).and_then(|data| self.deserializer.deserialize(&data));
+        *self.pending.lock().unwrap() = Some(value);
+    }
+}
+
+type Entry = Arc<Mutex<Box<Watch>>>;
+
+struct WatcherInner {
+    entries: Mutex<Vec<Entry>>,
+    interval: Duration,
+    last_poll: Mutex<Option<Instant>>,
+    // Set while a background poll job is running.
+    polling: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct Watcher {
+    inner: Arc<WatcherInner>,
+}
+
+impl Watcher {
+    /// Create a watcher checking for changes on every `poll` or `watch`.
+    pub fn new() -> Self {
+        Watcher::with_interval(Duration::from_secs(0))
+    }
+
+    /// Create a watcher checking for changes in `watch` at most once per interval.
+    pub fn with_interval(interval: Duration) -> Self {
+        Watcher {
+            inner: Arc::new(WatcherInner {
+                entries: Mutex::new(Vec::new()),
+                interval,
+                last_poll: Mutex::new(None),
+                polling: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Load a resource from a file and watch it for further changes.
+    pub fn load<T, D, P>(&self, path: P, deserializer: D) -> io::Result<HotResource<T>>
+    where
+        T: Send + 'static,
+        D: Deserializer<T>,
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let metadata = fs::metadata(&path)?;
+        let stamp = (metadata.modified()?, metadata.len());
+        let value = deserializer.deserialize(&fs::read(&path)?)?;
+        let pending = Arc::new(Mutex::new(None));
+
+        let entry: Box<Watch> = Box::new(WatchEntry {
+            path: path.clone(),
+            stamp: Some(stamp),
+            deserializer,
+            pending: pending.clone(),
+        });
+        self.inner.entries.lock().unwrap().push(Arc::new(Mutex::new(entry)));
+
+        Ok(HotResource {
+            value,
+            path,
+            pending,
+            error: None,
+        })
+    }
+
+    /// Check all watched files for changes.
+    ///
+    /// Entries of dropped resources are removed. Files are read without holding
+    /// the entry list lock, so loading new resources isn't blocked by the poll.
+    pub fn poll(&self) {
+        let entries = {
+            let mut entries = self.inner.entries.lock().unwrap();
+            entries.retain(|entry| entry.lock().unwrap().is_alive());
+            entries.clone()
+        };
+
+        for entry in entries {
+            entry.lock().unwrap().poll();
+        }
+    }
+
+    /// Spawn a background job on the pool checking all watched files for changes.
+    ///
+    /// Called once per frame, the job runs outside of the frame dependency tracking
+    /// and is skipped if the interval hasn't passed yet or the previous job is still
+    /// running. Changes are picked up by `reload` or `HotReload` in a later frame.
+    pub fn watch(&self, frame: &FrameBuilder) {
+        {
+            let mut last_poll = self.inner.last_poll.lock().unwrap();
+            let now = Instant::now();
+            if last_poll.map_or(false, |last| now - last < self.inner.interval) {
+                return;
+            }
+            if self.inner.polling.swap(true, Ordering::Acquire) {
+                return;
+            }
+            *last_poll = Some(now);
+        }
+
+        let watcher = self.clone();
+        let spawned = frame.scope().spawn(async move {
+            watcher.poll();
+            watcher.inner.polling.store(false, Ordering::Release);
+        });
+        if spawned.is_err() {
+            self.inner.polling.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Spawn a job applying pending changes of a hot resource.
+///
+/// The job accesses the resource exclusively, recording it at the start of a frame
+/// applies the change before any other job of the frame reads the resource.
+pub fn reload<T>(frame: &mut FrameBuilder, world: &WorldHandle) -> notify::Receiver
+where
+    HotResource<T>: Resource,
+{
+    let mut resource = world.query::<HotResource<T>>().read_write(frame);
+    frame.spawn_job(async move {
+        resource.apply();
+    })
+}
+
+/// System applying pending changes of a hot resource, see `reload`.
+pub struct HotReload<T>(PhantomData<fn() -> T>);
+
+impl<T> HotReload<T> {
+    pub fn new() -> Self {
+        HotReload(PhantomData)
+    }
+}
+
+impl<T> System for HotReload<T>
+where
+    HotResource<T>: Resource,
+{
+    type Data = Write<HotResource<T>>;
+
+    fn run(&mut self, mut resource: Self::Data) {
+        resource.apply();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    // Temporary file unique to the test and process.
+    fn temp_path(test: &str) -> PathBuf {
+        env::temp_dir().join(format!("tanya-hot-{}-{}.txt", process::id(), test))
+    }
+
+    fn parse(data: &[u8]) -> io::Result<u32> {
+        std::str::from_utf8(data)
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "expected integer"))
+    }
+
+    #[test]
+    fn reload_on_change() {
+        let path = temp_path("reload_on_change");
+        fs::write(&path, "1").unwrap();
+
+        let watcher = Watcher::new();
+        let mut resource = watcher.load(&path, parse).unwrap();
+        assert_eq!(*resource, 1);
+
+        watcher.poll();
+        assert!(!resource.apply());
+
+        fs::write(&path, "42").unwrap();
+        watcher.poll();
+        assert_eq!(*resource, 1);
+        assert!(resource.apply());
+        assert_eq!(*resource, 42);
+
+        fs::write(&path, "foo").unwrap();
+        watcher.poll();
+        assert!(!resource.apply());
+        assert!(resource.error().is_some());
+        assert_eq!(*resource, 42);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn remove_dropped() {
+        let path = temp_path("remove_dropped");
+        fs::write(&path, "1").unwrap();
+
+        let watcher = Watcher::new();
+        let resource = watcher.load(&path, parse).unwrap();
+        let other = watcher.load(&path, parse).unwrap();
+        assert_eq!(watcher.inner.entries.lock().unwrap().len(), 2);
+
+        drop(resource);
+        watcher.poll();
+        assert_eq!(watcher.inner.entries.lock().unwrap().len(), 1);
+
+        drop(other);
+        watcher.poll();
+        assert!(watcher.inner.entries.lock().unwrap().is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,387 @@
+//! Asynchronous file IO on dedicated threads.
+//!
+//! Jobs shouldn't block compute workers on `std::fs`. Reads are queued to a
+//! small set of IO threads instead, which also bounds the number of concurrent
+//! reads. Reads issued through an `IoFrame` can be cancelled together. Handles
+//! created by `FrameBuilder::io` are cancelled with the frame requesting them.
+//!
+//! ```ignore
+//! let io = frame.io(&service);
+//! frame.spawn_job(async move {
+//!     let data = await!(io.read_file("level.bin"));
+//!     // ..
+//! });
+//! ```
+
+use futures::channel::oneshot;
+use futures::task::{LocalWaker, Poll};
+use std::collections::VecDeque;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+struct Request {
+    path: PathBuf,
+    cancel: Option<Arc<AtomicBool>>,
+    sender: oneshot::Sender<io::Result<Vec<u8>>>,
+}
+
+impl Request {
+    fn is_cancelled(&self) -> bool {
+        self.sender.is_canceled()
+            || self
+                .cancel
+                .as_ref()
+                .map(|cancel| cancel.load(Ordering::Acquire))
+                .unwrap_or(false)
+    }
+}
+
+struct Inner {
+    queue: Mutex<VecDeque<Request>>,
+    cvar: Condvar,
+    shutdown: AtomicBool,
+}
+
+impl Inner {
+    fn submit<I>(&self, requests: I)
+    where
+        I: IntoIterator<Item = Request>,
+    {
+        let mut queue = self.queue.lock().unwrap();
+        let len = queue.len();
+        queue.extend(requests);
+        match queue.len() - len {
+            0 => (),
+            1 => self.cvar.notify_one(),
+            _ => self.cvar.notify_all(),
+        }
+    }
+
+    fn read(&self, path: &Path, cancel: &Option<Arc<AtomicBool>>) -> (Request, ReadFile) {
+        let (sender, recv) = oneshot::channel();
+        let request = Request {
+            path: path.to_path_buf(),
+            cancel: cancel.clone(),
+            sender,
+        };
+        (request, ReadFile { recv })
+    }
+
+    fn read_file(&self, path: &Path, cancel: &Option<Arc<AtomicBool>>) -> ReadFile {
+        let (request, file) = self.read(path, cancel);
+        self.submit(Some(request));
+        file
+    }
+
+    fn read_files<I>(&self, paths: I, cancel: &Option<Arc<AtomicBool>>) -> ReadBatch
+    where
+        I: IntoIterator,
+        I::Item: AsRef<Path>,
+    {
+        let (requests, files): (Vec<_>, Vec<_>) = paths
+            .into_iter()
+            .map(|path| self.read(path.as_ref(), cancel))
+            .unzip();
+        self.submit(requests);
+
+        ReadBatch {
+            results: files.iter().map(|_| None).collect(),
+            files: files.into_iter().map(Some).collect(),
+        }
+    }
+
+    fn run(&self) {
+        loop {
+            let request = {
+                let mut queue = self.queue.lock().unwrap();
+                loop {
+                    if self.shutdown.load(Ordering::Acquire) {
+                        return;
+                    }
+                    if let Some(request) = queue.pop_front() {
+                        break request;
+                    }
+                    queue = self.cvar.wait(queue).unwrap();
+                }
+            };
+
+            let result = if request.is_cancelled() {
+                Err(cancelled())
+            } else {
+                fs::read(&request.path)
+            };
+            let _ = request.sender.send(result);
+        }
+    }
+}
+
+fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "io request cancelled")
+}
+
+/// Pending read of a whole file.
+#[must_use = "futures do nothing unless polled"]
+pub struct ReadFile {
+    recv: oneshot::Receiver<io::Result<Vec<u8>>>,
+}
+
+impl Future for ReadFile {
+    type Output = io::Result<Vec<u8>>;
+
+    fn poll(mut self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<Self::Output> {
+        match Pin::new(&mut self.recv).poll(lw) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(cancelled())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Pending reads of multiple files, results are in request order.
+#[must_use = "futures do nothing unless polled"]
+pub struct ReadBatch {
+    files: Vec<Option<ReadFile>>,
+    results: Vec<Option<io::Result<Vec<u8>>>>,
+}
+
+impl Future for ReadBatch {
+    type Output = Vec<io::Result<Vec<u8>>>;
+
+    fn poll(mut self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut done = true;
+        for (file, result) in this.files.iter_mut().zip(this.results.iter_mut()) {
+            if let Some(pending) = file {
+                match Pin::new(pending).poll(lw) {
+                    Poll::Ready(data) => {
+                        *result = Some(data);
+                        *file = None;
+                    }
+                    Poll::Pending => done = false,
+                }
+            }
+        }
+
+        if done {
+            Poll::Ready(this.results.drain(..).map(Option::unwrap).collect())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+pub struct IoService {
+    inner: Arc<Inner>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl IoService {
+    /// Create a new service, at most `num_threads` files are read concurrently.
+    pub fn new(num_threads: usize) -> io::Result<Self> {
+        assert!(num_threads > 0);
+
+        let inner = Arc::new(Inner {
+            queue: Mutex::new(VecDeque::new()),
+            cvar: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let threads = (0..num_threads)
+            .map(|i| {
+                let inner = inner.clone();
+                thread::Builder::new()
+                    .name(format!("tanya-io-{}", i))
+                    .spawn(move || inner.run())
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(IoService { inner, threads })
+    }
+
+    /// Create a handle for issuing reads, which can be cancelled together.
+    pub fn frame(&self) -> IoFrame {
+        IoFrame {
+            inner: self.inner.clone(),
+            cancel: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn read_file<P: AsRef<Path>>(&self, path: P) -> ReadFile {
+        self.inner.read_file(path.as_ref(), &None)
+    }
+
+    pub fn read_files<I>(&self, paths: I) -> ReadBatch
+    where
+        I: IntoIterator,
+        I::Item: AsRef<Path>,
+    {
+        self.inner.read_files(paths, &None)
+    }
+}
+
+impl Drop for IoService {
+    fn drop(&mut self) {
+        {
+            let _queue = self.inner.queue.lock().unwrap();
+            self.inner.shutdown.store(true, Ordering::Release);
+            self.inner.cvar.notify_all();
+        }
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Handle for issuing reads from jobs of a frame.
+///
+/// Cloned handles share the same cancellation state.
+#[derive(Clone)]
+pub struct IoFrame {
+    inner: Arc<Inner>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl IoFrame {
+    pub fn read_file<P: AsRef<Path>>(&self, path: P) -> ReadFile {
+        self.inner.read_file(path.as_ref(), &Some(self.cancel.clone()))
+    }
+
+    pub fn read_files<I>(&self, paths: I) -> ReadBatch
+    where
+        I: IntoIterator,
+        I::Item: AsRef<Path>,
+    {
+        self.inner.read_files(paths, &Some(self.cancel.clone()))
+    }
+
+    /// Cancel all outstanding reads issued by this frame.
+    ///
+    /// Queued reads resolve with an `Interrupted` error, reads already in progress
+    /// finish normally.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn cancel_on_drop(&self) -> CancelGuard {
+        CancelGuard(self.cancel.clone())
+    }
+}
+
+/// Cancels the reads of an `IoFrame` when dropped.
+pub(crate) struct CancelGuard(Arc<AtomicBool>);
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::FrameBuilder;
+    use crate::jobs::{JobSystem, ThreadPoolBuilder};
+    use futures::executor::block_on;
+    use std::{env, process};
+
+    // Temporary file unique to the test and process.
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("tanya-io-{}-{}", process::id(), name))
+    }
+
+    fn write_temp(name: &str, data: &[u8]) -> PathBuf {
+        let path = temp_path(name);
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn read_file() {
+        let service = IoService::new(1).unwrap();
+        let path = write_temp("read_file.txt", b"hello");
+
+        assert_eq!(block_on(service.read_file(&path)).unwrap(), b"hello");
+        assert_eq!(
+            block_on(service.read_file(temp_path("read_file-missing.txt")))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_batch_order() {
+        // Large files complete after small ones, results are still in request order.
+        let service = IoService::new(4).unwrap();
+        let paths = (0..8)
+            .map(|i| {
+                let size = if i % 2 == 0 { 1 << 22 } else { 1 };
+                write_temp(&format!("read_batch_order-{}.bin", i), &vec![i as u8; size])
+            })
+            .collect::<Vec<_>>();
+
+        let results = block_on(service.read_files(&paths));
+        assert_eq!(results.len(), paths.len());
+        for (i, result) in results.into_iter().enumerate() {
+            let data = result.unwrap();
+            assert_eq!(data.len(), if i % 2 == 0 { 1 << 22 } else { 1 });
+            assert!(data.iter().all(|b| *b == i as u8));
+        }
+
+        for path in paths {
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn cancel_before_run() {
+        let service = IoService::new(1).unwrap();
+        let path = write_temp("cancel_before_run.txt", b"data");
+
+        let io = service.frame();
+        io.cancel();
+        assert_eq!(block_on(io.read_file(&path)).unwrap_err().kind(), io::ErrorKind::Interrupted);
+        for result in block_on(io.read_files(&[&path, &path])) {
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        }
+
+        // Reads of other handles aren't affected.
+        assert_eq!(block_on(service.read_file(&path)).unwrap(), b"data");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cancel_with_frame() {
+        let service = IoService::new(1).unwrap();
+        let path = write_temp("cancel_with_frame.txt", b"data");
+        let mut jobs = JobSystem::new(ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+
+        let io = jobs.scope(|scope| {
+            let mut frame = FrameBuilder::new(&scope);
+            let io = frame.io(&service);
+            assert_eq!(block_on(io.read_file(&path)).unwrap(), b"data");
+            // Discard the frame without dispatching.
+            io
+        });
+        assert_eq!(block_on(io.read_file(&path)).unwrap_err().kind(), io::ErrorKind::Interrupted);
+
+        let io = jobs.scope(|scope| {
+            let mut frame = FrameBuilder::new(&scope);
+            let io = frame.io(&service);
+            block_on(frame.dispatch());
+            io
+        });
+        assert_eq!(block_on(io.read_file(&path)).unwrap_err().kind(), io::ErrorKind::Interrupted);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,13 +1,13 @@
+use crate::executor::ThreadPool;
 use crate::notify;
 use futures::future::FutureObj;
 use futures::task::Spawn;
 use futures::task::SpawnError;
-use std::future::Future;
 use std::sync::{Arc, Mutex};
 
-pub use rayon::ThreadPoolBuilder;
+pub use crate::executor::ThreadPoolBuilder;
 
-pub struct PoolInner(pub rayon::ThreadPool);
+pub struct PoolInner(pub ThreadPool);
 pub type Pool = Arc<Mutex<PoolInner>>;
 
 pub struct JobSystem {
@@ -15,23 +15,28 @@ pub struct JobSystem {
 }
 
 impl JobSystem {
-    pub fn new(pool: rayon::ThreadPool) -> Self {
+    pub fn new(pool: ThreadPool) -> Self {
         JobSystem {
             pool: Arc::new(Mutex::new(PoolInner(pool))),
         }
     }
 
+    /// Run `op` with a scope for spawning jobs on the pool.
+    ///
+    /// `op` runs on the calling thread rather than on a pool worker. It only
+    /// builds frames and spawns jobs, running it on a worker would occupy that
+    /// worker and blocking inside `op` (e.g. on a frame) could starve or deadlock
+    /// the pool. Panics of spawned jobs are propagated to the jobs awaiting them.
     pub fn scope<OP, R>(&mut self, op: OP) -> R
     where
         OP: FnOnce(Scope) -> R + Send,
         R: Send,
     {
-        let registry = unsafe { self.pool.lock().unwrap().0.registry() };
         let tasks = Scope {
             pool: self.pool.clone(),
         };
 
-        registry.in_worker(|_, _| op(tasks))
+        op(tasks)
     }
 }
 
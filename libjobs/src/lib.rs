@@ -9,11 +9,15 @@
 
 pub extern crate futures;
 
+pub mod executor;
 pub mod frame;
+pub mod hot;
+pub mod io;
 pub mod jobs;
 pub mod notify;
 pub mod prelude;
 pub mod resource;
+pub mod system;
 pub mod world;
 
 #[macro_export]
@@ -64,9 +64,14 @@ pub struct Receiver {
     id: Option<usize>,
 }
 
+/// Signals the receivers when notified or dropped.
+///
+/// Dropping the sender without `notify`, e.g. while unwinding from a panicking
+/// job, fails the channel and all receivers panic when awaited.
 #[derive(Debug)]
 pub struct Sender {
     inner: Arc<Inner>,
+    notified: bool,
 }
 
 impl Clone for Receiver {
@@ -98,6 +103,7 @@ impl Unpin for Sender {}
 #[derive(Debug)]
 struct Inner {
     complete: AtomicBool,
+    failed: AtomicBool,
     rx_tasks: Lock<Vec<Option<Waker>>>,
 }
 
@@ -107,7 +113,10 @@ pub fn channel() -> (Sender, Receiver) {
         inner: inner.clone(),
         id: Some(0),
     };
-    let sender = Sender { inner };
+    let sender = Sender {
+        inner,
+        notified: false,
+    };
     (sender, receiver)
 }
 
@@ -115,11 +124,13 @@ impl Inner {
     fn new() -> Inner {
         Inner {
             complete: AtomicBool::new(false),
+            failed: AtomicBool::new(false),
             rx_tasks: Lock::new(vec![None]),
         }
     }
 
-    fn drop_tx(&self) {
+    fn drop_tx(&self, notified: bool) {
+        self.failed.store(!notified, SeqCst);
         self.complete.store(true, SeqCst);
         if let Some(mut slots) = self.rx_tasks.try_lock() {
             for slot in &mut *slots {
@@ -162,14 +173,26 @@ impl Inner {
 }
 
 impl Sender {
-    pub fn notify(self) {
+    pub fn notify(mut self) {
         // dropping
+        self.notified = true;
     }
 }
 
 impl Drop for Sender {
     fn drop(&mut self) {
-        self.inner.drop_tx()
+        self.inner.drop_tx(self.notified)
+    }
+}
+
+impl Receiver {
+    /// Poll for completion without panicking, returns if the job panicked.
+    fn poll_failed(&self, lw: &LocalWaker) -> Poll<bool> {
+        let poll = match self.id {
+            Some(id) => self.inner.recv(lw, id),
+            None => Poll::Ready(()),
+        };
+        poll.map(|()| self.inner.failed.load(SeqCst))
     }
 }
 
@@ -177,9 +200,55 @@ impl Future for Receiver {
     type Output = ();
 
     fn poll(self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<()> {
-        match self.id {
-            Some(id) => self.inner.recv(lw, id),
-            None => Poll::Ready(()),
+        match self.poll_failed(lw) {
+            Poll::Ready(true) => panic!("Awaited job panicked"),
+            Poll::Ready(false) => Poll::Ready(()),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Wait for all receivers, even if some of their jobs panicked.
+///
+/// Panics after all jobs completed if any of them panicked. Tasks borrowing
+/// data of the awaiting job are joined this way before the panic releases it.
+pub fn join_all(receivers: Vec<Receiver>) -> JoinAll {
+    JoinAll {
+        receivers,
+        failed: false,
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct JoinAll {
+    receivers: Vec<Receiver>,
+    failed: bool,
+}
+
+impl Unpin for JoinAll {}
+
+impl Future for JoinAll {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, lw: &LocalWaker) -> Poll<()> {
+        let mut i = 0;
+        while i < self.receivers.len() {
+            match self.receivers[i].poll_failed(lw) {
+                Poll::Ready(failed) => {
+                    self.failed |= failed;
+                    drop(self.receivers.swap_remove(i));
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+
+        if !self.receivers.is_empty() {
+            Poll::Pending
+        } else if self.failed {
+            panic!("Awaited job panicked");
+        } else {
+            Poll::Ready(())
         }
     }
 }
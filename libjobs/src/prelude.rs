@@ -1,6 +1,10 @@
 pub use crate::frame::FrameBuilder;
 pub use crate::futures;
 pub use crate::futures::prelude::*;
+pub use crate::hot::{HotReload, HotResource, Watcher};
+pub use crate::io::{IoFrame, IoService};
 pub use crate::jobs::{JobSystem, Scope, ThreadPoolBuilder};
 pub use crate::notify;
+pub use crate::resource::{Read, ReadWrite, Write};
+pub use crate::system::{Schedule, System};
 pub use crate::world::World;
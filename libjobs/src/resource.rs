@@ -39,6 +39,8 @@ impl<R: Resource> std::ops::Deref for Read<R> {
     }
 }
 
+pub type Write<R> = ReadWrite<R>;
+
 pub struct ReadWrite<R>(*mut Box<Resource>, std::marker::PhantomData<R>);
 unsafe impl<R> Send for ReadWrite<R> {}
 
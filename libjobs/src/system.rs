@@ -0,0 +1,390 @@
+//! Systems declare the resources they access and are turned into frame jobs
+//! by a `Schedule`.
+//!
+//! ```ignore
+//! struct Integrate;
+//!
+//! impl System for Integrate {
+//!     type Data = (Read<Time>, Write<Positions>);
+//!
+//!     fn run(&mut self, (time, mut positions): Self::Data) {
+//!         // ..
+//!     }
+//! }
+//!
+//! let mut schedule = Schedule::new();
+//! schedule.add_system(Input).label("input");
+//! schedule.add_system(Integrate).after("input");
+//!
+//! // every frame
+//! let mut frame = FrameBuilder::new(&jobs);
+//! schedule.run(&mut frame, &mut world);
+//! frame.dispatch()
+//! ```
+
+use crate::frame::{FrameBuilder, WorldHandle};
+use crate::notify;
+use crate::resource::{Read, ReadWrite, Resource};
+use crate::world::World;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub trait System: Send + 'static {
+    /// Resources accessed by the system, e.g. `(Read<A>, Write<B>)`.
+    type Data: SystemData;
+
+    fn run(&mut self, data: Self::Data);
+}
+
+/// Resource access of a system.
+///
+/// Fetching registers the access at the frame builder, which derives the
+/// dependencies to previously spawned jobs.
+pub trait SystemData: Send + Sized + 'static {
+    fn fetch(world: &WorldHandle, frame: &FrameBuilder) -> Self;
+}
+
+impl<R: Resource> SystemData for Read<R> {
+    fn fetch(world: &WorldHandle, frame: &FrameBuilder) -> Self {
+        world.query::<R>().read(frame)
+    }
+}
+
+impl<R: Resource> SystemData for ReadWrite<R> {
+    fn fetch(world: &WorldHandle, frame: &FrameBuilder) -> Self {
+        world.query::<R>().read_write(frame)
+    }
+}
+
+macro_rules! impl_system_data {
+    ($($ty:ident),*) => {
+        impl<$($ty: SystemData),*> SystemData for ($($ty,)*) {
+            #[allow(unused_variables)]
+            fn fetch(world: &WorldHandle, frame: &FrameBuilder) -> Self {
+                ($($ty::fetch(world, frame),)*)
+            }
+        }
+    };
+}
+
+impl_system_data!();
+impl_system_data!(A);
+impl_system_data!(A, B);
+impl_system_data!(A, B, C);
+impl_system_data!(A, B, C, D);
+impl_system_data!(A, B, C, D, E);
+impl_system_data!(A, B, C, D, E, F);
+impl_system_data!(A, B, C, D, E, F, G);
+impl_system_data!(A, B, C, D, E, F, G, H);
+
+trait SpawnSystem: Send {
+    fn spawn(
+        &self,
+        world: &WorldHandle,
+        frame: &mut FrameBuilder,
+        deps: Vec<notify::Receiver>,
+    ) -> notify::Receiver;
+}
+
+struct SystemJob<S>(Arc<Mutex<S>>);
+
+impl<S: System> SpawnSystem for SystemJob<S> {
+    fn spawn(
+        &self,
+        world: &WorldHandle,
+        frame: &mut FrameBuilder,
+        deps: Vec<notify::Receiver>,
+    ) -> notify::Receiver {
+        let data = S::Data::fetch(world, frame);
+        let system = self.0.clone();
+        frame.spawn_job(async move {
+            for dep in deps {
+                await!(dep);
+            }
+            system.lock().unwrap().run(data);
+        })
+    }
+}
+
+struct SystemEntry {
+    system: Box<SpawnSystem>,
+    labels: Vec<&'static str>,
+    after: Vec<&'static str>,
+    before: Vec<&'static str>,
+}
+
+/// Ordering configuration of a newly added system.
+pub struct SystemConfig<'a> {
+    entry: &'a mut SystemEntry,
+}
+
+impl<'a> SystemConfig<'a> {
+    /// Attach a label, which can be referenced by ordering constraints of other systems.
+    pub fn label(self, label: &'static str) -> Self {
+        self.entry.labels.push(label);
+        self
+    }
+
+    /// Run after all systems with the given label.
+    pub fn after(self, label: &'static str) -> Self {
+        self.entry.after.push(label);
+        self
+    }
+
+    /// Run before all systems with the given label.
+    pub fn before(self, label: &'static str) -> Self {
+        self.entry.before.push(label);
+        self
+    }
+}
+
+pub struct Schedule {
+    systems: Vec<SystemEntry>,
+    // Topological order of `systems` and the explicit dependencies of each system.
+    order: Option<Vec<(usize, Vec<usize>)>>,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Schedule {
+            systems: Vec::new(),
+            order: None,
+        }
+    }
+
+    pub fn add_system<S: System>(&mut self, system: S) -> SystemConfig {
+        self.order = None;
+        self.systems.push(SystemEntry {
+            system: Box::new(SystemJob(Arc::new(Mutex::new(system)))),
+            labels: Vec::new(),
+            after: Vec::new(),
+            before: Vec::new(),
+        });
+
+        SystemConfig {
+            entry: self.systems.last_mut().unwrap(),
+        }
+    }
+
+    /// Spawn a job for each system on the frame.
+    ///
+    /// Systems are spawned in insertion order unless reordered by label constraints.
+    /// Data dependencies between the systems are derived by the frame builder.
+    pub fn run(&mut self, frame: &mut FrameBuilder, world: &mut World) {
+        if self.order.is_none() {
+            self.order = Some(self.build_order());
+        }
+
+        let world = frame.access(world);
+        let mut jobs: Vec<Option<notify::Receiver>> = self.systems.iter().map(|_| None).collect();
+        for (system, deps) in self.order.as_ref().unwrap() {
+            let deps = deps
+                .iter()
+                .map(|dep| jobs[*dep].as_ref().unwrap().clone())
+                .collect();
+            jobs[*system] = Some(self.systems[*system].system.spawn(&world, frame, deps));
+        }
+    }
+
+    fn build_order(&self) -> Vec<(usize, Vec<usize>)> {
+        let mut labels = HashMap::<&'static str, Vec<usize>>::new();
+        for (i, system) in self.systems.iter().enumerate() {
+            for label in &system.labels {
+                labels.entry(*label).or_insert_with(Vec::new).push(i);
+            }
+        }
+
+        let mut deps = vec![Vec::new(); self.systems.len()];
+        for (i, system) in self.systems.iter().enumerate() {
+            for label in &system.after {
+                if let Some(systems) = labels.get(label) {
+                    deps[i].extend(systems.iter().filter(|s| **s != i));
+                }
+            }
+            for label in &system.before {
+                if let Some(systems) = labels.get(label) {
+                    for s in systems.iter().filter(|s| **s != i) {
+                        deps[*s].push(i);
+                    }
+                }
+            }
+        }
+        for dep in &mut deps {
+            dep.sort();
+            dep.dedup();
+        }
+
+        // Kahn's algorithm, picking the earliest added system among the ready ones.
+        let mut num_deps = deps.iter().map(|d| d.len()).collect::<Vec<_>>();
+        let mut order = Vec::with_capacity(self.systems.len());
+        let mut scheduled = vec![false; self.systems.len()];
+        while order.len() < self.systems.len() {
+            let next = (0..self.systems.len())
+                .find(|i| !scheduled[*i] && num_deps[*i] == 0)
+                .unwrap_or_else(|| panic!("Cyclic ordering constraints between systems"));
+
+            scheduled[next] = true;
+            for (i, dep) in deps.iter().enumerate() {
+                if dep.contains(&next) {
+                    num_deps[i] -= 1;
+                }
+            }
+            order.push((next, deps[next].clone()));
+        }
+
+        order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::{JobSystem, ThreadPoolBuilder};
+    use crate::resource::Write;
+    use futures::executor::block_on;
+    use std::thread;
+    use std::time::Duration;
+
+    type Log = Arc<Mutex<Vec<&'static str>>>;
+
+    struct Noop;
+
+    impl System for Noop {
+        type Data = ();
+
+        fn run(&mut self, _: ()) {}
+    }
+
+    fn order(schedule: &Schedule) -> Vec<usize> {
+        schedule.build_order().into_iter().map(|(system, _)| system).collect()
+    }
+
+    #[test]
+    fn insertion_order() {
+        let mut schedule = Schedule::new();
+        for _ in 0..4 {
+            schedule.add_system(Noop);
+        }
+        assert_eq!(order(&schedule), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn after_label() {
+        let mut schedule = Schedule::new();
+        schedule.add_system(Noop).after("input");
+        schedule.add_system(Noop).label("input");
+        schedule.add_system(Noop).label("input");
+        schedule.add_system(Noop);
+
+        let order = schedule.build_order();
+        assert_eq!(order.iter().map(|(s, _)| *s).collect::<Vec<_>>(), vec![1, 2, 0, 3]);
+        assert_eq!(order[2], (0, vec![1, 2]));
+    }
+
+    #[test]
+    fn before_label() {
+        let mut schedule = Schedule::new();
+        schedule.add_system(Noop).label("render");
+        schedule.add_system(Noop);
+        schedule.add_system(Noop).before("render");
+
+        let order = schedule.build_order();
+        assert_eq!(order.iter().map(|(s, _)| *s).collect::<Vec<_>>(), vec![1, 2, 0]);
+        assert_eq!(order[2], (0, vec![2]));
+    }
+
+    #[test]
+    fn tie_break_insertion_order() {
+        // Both 1 and 2 become ready after 0, the earlier added one runs first.
+        let mut schedule = Schedule::new();
+        schedule.add_system(Noop).after("b");
+        schedule.add_system(Noop).label("a").after("c");
+        schedule.add_system(Noop).label("b").after("c");
+        schedule.add_system(Noop).label("c");
+        assert_eq!(order(&schedule), vec![3, 1, 2, 0]);
+    }
+
+    #[test]
+    fn self_label() {
+        let mut schedule = Schedule::new();
+        // Constraints on a system's own label are ignored.
+        schedule.add_system(Noop).label("a").after("a").before("a");
+        schedule.add_system(Noop).after("a");
+        assert_eq!(order(&schedule), vec![0, 1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cyclic ordering constraints")]
+    fn cyclic_constraints() {
+        let mut schedule = Schedule::new();
+        schedule.add_system(Noop).label("a").after("b");
+        schedule.add_system(Noop).label("b").before("a").after("a");
+        schedule.build_order();
+    }
+
+    struct Logged {
+        log: Log,
+        name: &'static str,
+        delay: u64,
+    }
+
+    impl System for Logged {
+        type Data = ();
+
+        fn run(&mut self, _: ()) {
+            thread::sleep(Duration::from_millis(self.delay));
+            self.log.lock().unwrap().push(self.name);
+        }
+    }
+
+    struct Increment {
+        log: Log,
+        names: (&'static str, &'static str),
+    }
+
+    impl System for Increment {
+        type Data = Write<u32>;
+
+        fn run(&mut self, mut counter: Write<u32>) {
+            self.log.lock().unwrap().push(self.names.0);
+            let value = *counter;
+            thread::sleep(Duration::from_millis(50));
+            *counter = value + 1;
+            self.log.lock().unwrap().push(self.names.1);
+        }
+    }
+
+    #[test]
+    fn run_frame() {
+        let log = Log::default();
+        let mut schedule = Schedule::new();
+        // Without data dependencies only the labels order the systems.
+        schedule
+            .add_system(Logged { log: log.clone(), name: "update", delay: 0 })
+            .after("input");
+        schedule
+            .add_system(Logged { log: log.clone(), name: "input", delay: 100 })
+            .label("input");
+        schedule.add_system(Increment { log: log.clone(), names: ("first", "first done") });
+        schedule.add_system(Increment { log: log.clone(), names: ("second", "second done") });
+
+        let mut jobs = JobSystem::new(ThreadPoolBuilder::new().num_threads(4).build().unwrap());
+        let mut world = World::new();
+        world.add_resource(0u32);
+        jobs.scope(|scope| {
+            let mut frame = FrameBuilder::new(&scope);
+            schedule.run(&mut frame, &mut world);
+            block_on(frame.dispatch());
+        });
+
+        let log = log.lock().unwrap();
+        let labeled = log.iter().filter(|name| ["input", "update"].contains(name));
+        assert_eq!(labeled.collect::<Vec<_>>(), vec![&"input", &"update"]);
+        // Both systems write the counter and don't overlap.
+        let writes = log.iter().filter(|name| !["input", "update"].contains(name));
+        assert_eq!(
+            writes.collect::<Vec<_>>(),
+            vec![&"first", &"first done", &"second", &"second done"]
+        );
+    }
+}
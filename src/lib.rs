@@ -1,5 +1,6 @@
 pub extern crate tanya_ecs as ecs;
+pub extern crate tanya_ecs2 as ecs2;
 pub extern crate tanya_jobs as jobs;
 pub extern crate tanya_render as render;
 pub extern crate tanya_ui as ui;
-pub use tanya_ecs_derive::Component;
+pub use tanya_ecs_derive::{Component, ComponentGroup, Reflect};